@@ -1,14 +1,21 @@
 use colored::*;
 use gumdrop::Options;
 use log::*;
-use std::collections::HashMap;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use zap_model::inventory::Inventory;
-use zap_model::transport::ssh::Ssh;
+use zap_model::inventory::{Group, Inventory, Target, Transport};
+use zap_model::transport::ssh::{HostKeyStatus, Ssh};
 use zap_model::ExecutableTask;
-use zap_model::{Plan, Task, Transport};
+use zap_model::{Event, GroupResults, Plan, Runner, Task};
+
+mod output;
+
+use output::{print_recap, print_summary, Format, Output};
+
+/// Groups are run one target at a time unless told otherwise
+const DEFAULT_PARALLEL: usize = 1;
 
 fn main() {
     pretty_env_logger::init();
@@ -18,21 +25,23 @@ fn main() {
         println!("Must specify a subcommand!");
         std::process::exit(1);
     }
+    let command = opts.command.unwrap();
+    let output = Output::new(command.format());
 
-    let file =
-        std::fs::File::open("inventory.yml").expect("Failed to load the inventory.ymll file");
-    let reader = BufReader::new(file);
-    let inventory: Inventory = serde_yaml::from_reader(reader).expect("Failed to read intenvory");
-
-    let mut runner = match &inventory.config.transport {
-        zap_model::inventory::Transport::Ssh => Ssh::default(),
+    let inventory = match Inventory::from_path(Path::new("inventory.yml")) {
+        Ok(inventory) => inventory,
+        Err(err) => {
+            output.error(format!("{}", err));
+            output.exit(1);
+        }
     };
 
-    match opts.command.unwrap() {
-        Command::Cmd(opts) => handle_cmd(opts, &mut runner, inventory),
-        Command::Task(opts) => handle_task(opts, &mut runner, inventory),
-        Command::Plan(opts) => handle_plan(opts, &mut runner, inventory),
-        Command::Check(opts) => handle_check(opts),
+    match command {
+        Command::Cmd(opts) => handle_cmd(opts, inventory, output),
+        Command::Task(opts) => handle_task(opts, inventory, output),
+        Command::Plan(opts) => handle_plan(opts, inventory, output),
+        Command::Check(opts) => handle_check(opts, output),
+        Command::Keyscan(opts) => handle_keyscan(opts, inventory),
         _ => {}
     }
 }
@@ -41,101 +50,356 @@ fn main() {
  * This function simply attempts to parse the given ztask or zplan files in order
  * to validate that they're properly formatted
  */
-fn handle_check(opts: CheckOpts) {
+fn handle_check(opts: CheckOpts, output: Arc<Output>) {
+    let mut exit = 0;
     for file in opts.files.iter() {
-        if let Some(ext) = file.as_path().extension() {
-            if ext == "ztask" {
-                let task = Task::from_path(&file).expect("Failed to parse");
-                println!("Parsed task {} properly", task.name);
-            }
-            if ext == "zplan" {
-                let _plan = Plan::from_path(&file).expect("Failed to parse");
-                println!("Parsed plan properly");
+        let error = match file.extension().and_then(|ext| ext.to_str()) {
+            Some("ztask") => Task::from_path(file).err().map(|e| e.to_string()),
+            Some("zplan") => Plan::from_path(file).err().map(|e| e.to_string()),
+            _ => Some("only .ztask and .zplan files can be checked".into()),
+        };
+        if error.is_some() {
+            exit = 1;
+        }
+        output.report(&Event::Check {
+            file: file.display().to_string(),
+            error,
+        });
+    }
+    output.exit(exit);
+}
+
+/**
+ * This function will record the host keys of the targets identified in the
+ * `opts` into their known_hosts file, or of every target in the inventory if
+ * none were given.
+ *
+ * Host keys which differ from those already on record are reported and cause
+ * a non-zero exit, they are never replaced.
+ */
+fn handle_keyscan(opts: KeyscanOpts, inventory: Inventory) {
+    // Members of a group are scanned with their group's config, which may well
+    // set their known_hosts or proxy_jump
+    let pattern = if opts.targets.is_empty() {
+        "*"
+    } else {
+        &opts.targets
+    };
+    let targets = inventory.select(pattern);
+    if targets.is_empty() {
+        println!(
+            "{}",
+            format!("No target or group named `{}` in the inventory", pattern).red()
+        );
+        std::process::exit(1);
+    }
+
+    let mut exit = 0;
+    for target in targets.iter() {
+        let name = &target.name;
+        if !reached_over_ssh(target) {
+            println!("Skipping `{}`: not an ssh target", name);
+            continue;
+        }
+        match Ssh::keyscan(target) {
+            Ok(HostKeyStatus::Added) => println!("Added the host key for `{}`", name),
+            Ok(HostKeyStatus::Known) => println!("The host key for `{}` is already known", name),
+            Err(err) => {
+                println!("{}", format!("Failed on `{}`: {}", name, err).red());
+                exit = 1;
             }
         }
     }
+    std::process::exit(exit);
+}
+
+/**
+ * Whether the target, or the host it is jailed or chrooted on, is connected
+ * to over ssh
+ */
+fn reached_over_ssh(target: &Target) -> bool {
+    let transport = target.transport();
+    if transport.is_wrapper() {
+        target.via() == Transport::Ssh
+    } else {
+        transport == Transport::Ssh
+    }
 }
 
 /**
  * This function will parse and execute a plan
+ *
+ * A target which a task fails on is left out of the rest of the plan, unless
+ * the task ignores errors or `--keep-going` was given. Either way the plan
+ * exits with the status of the first task which failed without ignoring it.
  */
-fn handle_plan(opts: PlanOpts, runner: &mut dyn Transport, inventory: Inventory) {
-    println!("{}", format!("Running plan with: {:?}", opts).green());
+fn handle_plan(opts: PlanOpts, inventory: Inventory, output: Arc<Output>) {
+    if output.is_human() {
+        println!("{}", format!("Running plan with: {:?}", opts).green());
+    }
     let mut exit: i32 = -1;
+    let runner = runner(&inventory, &output, opts.dry_run, opts.parallel);
 
-    match Plan::from_path(&opts.plan) {
+    let plan = Plan::from_path(&opts.plan)
+        .map_err(|err| format!("Failed to load plan: {}", err))
+        .and_then(|plan| plan.with_parameters(parse_parameters(&opts.parameter)?))
+        .and_then(|plan| {
+            plan.validate(&inventory, opts.targets.as_deref())
+                .map(|_| plan)
+        });
+    match plan {
         Ok(plan) => {
             info!("Plan located, preparing to execute");
+            exit = 0;
+            // Targets which the rest of the plan isn't run on, after a failure
+            let mut stopped = HashSet::new();
             for task in plan.tasks {
                 info!("Running executable task: {:?}", task);
-                exit = execute_task_on(
-                    opts.targets.clone(),
-                    &task,
-                    runner,
-                    &inventory,
-                    opts.dry_run,
-                );
+                let (status, results) =
+                    execute_step(opts.targets.as_deref(), &task, &runner, &output, &stopped);
+                if task.ignore_errors {
+                    continue;
+                }
+                if exit == 0 {
+                    exit = status;
+                }
+                if !opts.keep_going {
+                    stopped.extend(
+                        results
+                            .iter()
+                            .filter(|(_, outcome)| !matches!(outcome, Ok(r) if r.success()))
+                            .map(|(name, _)| name.clone()),
+                    );
+                }
+            }
+            if output.is_human() {
+                print_recap(&output.results());
             }
         }
-        Err(err) => {
-            println!("Failed to load plan: {:?}", err);
-        }
+        Err(err) => output.error(err),
     }
-    std::process::exit(exit);
+    // Every task of the plan shares the connections to the targets
+    runner.disconnect();
+    output.exit(exit);
+}
+
+/**
+ * Turn `name=value` pairs from the command line into parameters, the value may
+ * contain `=` itself
+ */
+fn parse_parameters(pairs: &[String]) -> Result<HashMap<String, String>, String> {
+    pairs
+        .iter()
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) => Ok((name.to_string(), value.to_string())),
+            None => Err(format!(
+                "Parameters are given as `name=value`, not `{}`",
+                pair
+            )),
+        })
+        .collect()
 }
 
+/**
+ * Set up a runner which reports to the output
+ */
+fn runner<'a>(
+    inventory: &'a Inventory,
+    output: &Arc<Output>,
+    dry_run: bool,
+    parallel: Option<usize>,
+) -> Runner<'a> {
+    let reporter = output.clone();
+    let mut runner = Runner::new(inventory).with_reporter(move |event| reporter.report(event));
+    runner.dry_run = dry_run;
+    runner.parallel = parallelism(parallel, inventory);
+    runner
+}
+
+/**
+ * The number of targets in a group to run on at once, from the command line or
+ * else the inventory
+ */
+fn parallelism(parallel: Option<usize>, inventory: &Inventory) -> usize {
+    parallel
+        .or(inventory.config.parallel)
+        .unwrap_or(DEFAULT_PARALLEL)
+}
+
+/**
+ * Run the task on the named target or group, except for any targets which have
+ * been stopped, and return the status to exit with along with how the task
+ * went on each target. The results are reported to the output along the way.
+ */
 fn execute_task_on(
-    targets: String,
+    targets: &str,
     task: &ExecutableTask,
-    runner: &mut dyn Transport,
-    inventory: &Inventory,
-    dry_run: bool,
-) -> i32 {
+    runner: &Runner,
+    output: &Output,
+    stopped: &HashSet<String>,
+) -> (i32, GroupResults) {
+    let inventory = runner.inventory;
+    let skip = |name: &String| is_stopped(name, task, output, stopped);
+
     if let Some(group) = inventory.groups.iter().find(|g| g.name == targets) {
-        return runner.run_group(task, &group, &inventory, dry_run);
+        let group = Group {
+            targets: group.targets.iter().filter(|t| !skip(t)).cloned().collect(),
+            ..group.clone()
+        };
+        if group.targets.is_empty() {
+            return (0, GroupResults::new());
+        }
+        let results = runner.run_group(task, &group);
+        if output.is_human() {
+            print_summary(&results);
+        }
+        return (group_status(&results), results);
     }
 
-    if let Some(target) = inventory.targets.iter().find(|t| t.name == targets) {
-        return runner.run(task, &target, dry_run);
+    if let Some(target) = inventory.target(targets) {
+        let mut results = GroupResults::new();
+        if skip(&target.name) {
+            return (0, results);
+        }
+        let outcome = runner.run(task, &target);
+        let status = match &outcome {
+            Ok(result) => result.exit_code,
+            Err(_) => -1,
+        };
+        results.insert(target.name.clone(), outcome);
+        return (status, results);
+    }
+    output.error(format!(
+        "No target or group named `{}` in the inventory",
+        targets
+    ));
+    (-1, GroupResults::new())
+}
+
+/**
+ * Run a task of a plan on the targets it selects with `on`, narrowed down to
+ * those which the plan is run on if it was given any, or else on the target or
+ * group the plan is run on
+ */
+fn execute_step(
+    targets: Option<&str>,
+    task: &ExecutableTask,
+    runner: &Runner,
+    output: &Output,
+    stopped: &HashSet<String>,
+) -> (i32, GroupResults) {
+    if task.on.is_empty() {
+        // The plan has been validated to have targets for tasks without `on`
+        let targets = targets.unwrap_or_default();
+        return execute_task_on(targets, task, runner, output, stopped);
+    }
+
+    let inventory = runner.inventory;
+    let allowed: Option<HashSet<String>> = targets.map(|targets| {
+        inventory
+            .select(targets)
+            .into_iter()
+            .map(|t| t.name)
+            .collect()
+    });
+    let mut selected: Vec<Target> = vec![];
+    for target in task.on.iter().flat_map(|pattern| inventory.select(pattern)) {
+        let included = allowed.as_ref().is_none_or(|a| a.contains(&target.name));
+        if included && !selected.iter().any(|t| t.name == target.name) {
+            selected.push(target);
+        }
+    }
+    if selected.is_empty() && output.is_human() {
+        println!(
+            "{}",
+            format!(
+                "Skipping `{}`, none of the targets it runs on are in `{}`",
+                task.task.name,
+                targets.unwrap_or_default()
+            )
+            .yellow()
+        );
+    }
+    selected.retain(|target| !is_stopped(&target.name, task, output, stopped));
+    if selected.is_empty() {
+        return (0, GroupResults::new());
+    }
+
+    let results = runner.run_targets(task, &task.on.join(", "), &selected);
+    if output.is_human() {
+        print_summary(&results);
+    }
+    (group_status(&results), results)
+}
+
+/**
+ * Whether the rest of the plan has been stopped on the named target, which is
+ * pointed out when it is skipped over
+ */
+fn is_stopped(
+    name: &str,
+    task: &ExecutableTask,
+    output: &Output,
+    stopped: &HashSet<String>,
+) -> bool {
+    let skipped = stopped.contains(name);
+    if skipped && output.is_human() {
+        println!(
+            "{}",
+            format!(
+                "Skipping `{}` on `{}`, an earlier task failed there",
+                task.task.name, name
+            )
+            .yellow()
+        );
+    }
+    skipped
+}
+
+/**
+ * A group only succeeds when the task succeeded on every one of its targets
+ */
+fn group_status(results: &GroupResults) -> i32 {
+    if results
+        .values()
+        .all(|outcome| matches!(outcome, Ok(r) if r.success()))
+    {
+        0
+    } else {
+        1
     }
-    error!("Failed to locate a script to execute for the task!");
-    return -1;
 }
 
 /**
  * This function will handle a task
  */
-fn handle_task(opts: TaskOpts, runner: &mut dyn Transport, inventory: Inventory) {
-    println!("{}", format!("Running task with: {:?}", opts).green());
+fn handle_task(opts: TaskOpts, inventory: Inventory, output: Arc<Output>) {
+    if output.is_human() {
+        println!("{}", format!("Running task with: {:?}", opts).green());
+    }
 
     match Task::from_path(&opts.task) {
         Ok(task) => {
             info!("Task located, preparing to execute");
-            let mut parameters = HashMap::new();
-
-            /*
-             * XXX: This is very primitive way, there must be a better way to take
-             * arbitrary command line parameters than this.
-             */
-            for parameter in opts.parameter.iter() {
-                let parts: Vec<&str> = parameter.split("=").collect();
-                if parts.len() == 2 {
-                    parameters.insert(parts[0].to_string(), parts[1].to_string());
+            let parameters = match parse_parameters(&opts.parameter) {
+                Ok(parameters) => parameters,
+                Err(err) => {
+                    output.error(err);
+                    output.exit(1);
                 }
-            }
+            };
 
             let task = ExecutableTask::new(task, parameters);
 
-            std::process::exit(execute_task_on(
-                opts.targets,
-                &task,
-                runner,
-                &inventory,
-                opts.dry_run,
-            ));
+            let runner = runner(&inventory, &output, opts.dry_run, opts.parallel);
+            let (status, _) =
+                execute_task_on(&opts.targets, &task, &runner, &output, &HashSet::new());
+            runner.disconnect();
+            output.exit(status);
         }
         Err(err) => {
-            println!("Failed to load task: {:?}", err);
+            output.error(format!("Failed to load task: {}", err));
+            output.exit(1);
         }
     }
 }
@@ -150,16 +414,13 @@ fn handle_task(opts: TaskOpts, runner: &mut dyn Transport, inventory: Inventory)
  * In the case of multiple targets, any non-zero status code will be used to exit
  * non-zero.
  */
-fn handle_cmd(opts: CmdOpts, runner: &mut dyn Transport, inventory: Inventory) {
+fn handle_cmd(opts: CmdOpts, inventory: Inventory, output: Arc<Output>) {
     let mut task = ExecutableTask::new(Task::new("Dynamic"), HashMap::new());
     task.task.script.inline = Some(opts.command);
-    std::process::exit(execute_task_on(
-        opts.targets,
-        &task,
-        runner,
-        &inventory,
-        false,
-    ));
+    let runner = runner(&inventory, &output, false, opts.parallel);
+    let (status, _) = execute_task_on(&opts.targets, &task, &runner, &output, &HashSet::new());
+    runner.disconnect();
+    output.exit(status);
 }
 
 #[derive(Debug, Options)]
@@ -199,6 +460,23 @@ enum Command {
     Plan(PlanOpts),
     #[options(help = "Check that the specified .ztask or .zplan file is valid")]
     Check(CheckOpts),
+    #[options(help = "Record the host keys of a target(s) in known_hosts")]
+    Keyscan(KeyscanOpts),
+}
+
+impl Command {
+    /**
+     * The format the command's output was asked for in
+     */
+    fn format(&self) -> Format {
+        match self {
+            Command::Cmd(opts) => opts.format,
+            Command::Task(opts) => opts.format,
+            Command::Plan(opts) => opts.format,
+            Command::Check(opts) => opts.format,
+            Command::Help(_) | Command::Keyscan(_) => Format::Human,
+        }
+    }
 }
 
 #[derive(Debug, Options)]
@@ -212,6 +490,10 @@ struct CmdOpts {
     command: String,
     #[options(help = "Name of a target or group")]
     targets: String,
+    #[options(no_short, help = "How many targets of a group to run on at once")]
+    parallel: Option<usize>,
+    #[options(no_short, help = "Output format: human, json or jsonl")]
+    format: Format,
 }
 
 #[derive(Debug, Options)]
@@ -224,23 +506,132 @@ struct TaskOpts {
     targets: String,
     #[options(help = "Run the task in dry-run mode")]
     dry_run: bool,
+    #[options(no_short, help = "How many targets of a group to run on at once")]
+    parallel: Option<usize>,
+    #[options(no_short, help = "Output format: human, json or jsonl")]
+    format: Format,
 }
 
 #[derive(Debug, Options)]
 struct PlanOpts {
     #[options(free, help = "Plan to execute")]
     plan: PathBuf,
-    #[options(help = "Name of a target or group")]
-    targets: String,
+    #[options(
+        short = "p",
+        long = "param",
+        help = "Parameter values, e.g. version=1.2"
+    )]
+    parameter: Vec<String>,
+    #[options(help = "Name of a target or group, which tasks with `on` are narrowed down to")]
+    targets: Option<String>,
     #[options(help = "Run the task in dry-run mode")]
     dry_run: bool,
+    #[options(help = "Keep running the plan on targets where a task failed")]
+    keep_going: bool,
+    #[options(no_short, help = "How many targets of a group to run on at once")]
+    parallel: Option<usize>,
+    #[options(no_short, help = "Output format: human, json or jsonl")]
+    format: Format,
 }
 
 #[derive(Debug, Options)]
 struct CheckOpts {
     #[options(free, help = "Files to check")]
     files: Vec<PathBuf>,
+    #[options(no_short, help = "Output format: human, json or jsonl")]
+    format: Format,
+}
+
+#[derive(Debug, Options)]
+struct KeyscanOpts {
+    #[options(help = "Name of a target or group, defaults to every target")]
+    targets: String,
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use zap_model::{ExecutionResult, TransportError};
+
+    fn exited(exit_code: i32) -> Result<ExecutionResult, TransportError> {
+        Ok(ExecutionResult {
+            exit_code,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn parse_parameter_pairs() {
+        let pairs = vec!["version=1.2".to_string(), "flags=-j4 X=1".to_string()];
+        let parameters = parse_parameters(&pairs).expect("Failed to parse");
+        assert_eq!(parameters["version"], "1.2");
+        assert_eq!(parameters["flags"], "-j4 X=1");
+        assert!(parse_parameters(&["version".to_string()]).is_err());
+    }
+
+    #[test]
+    fn parse_parameter_without_value() {
+        let pairs = vec!["msg=hello".to_string(), "verbose".to_string()];
+        assert_eq!(
+            parse_parameters(&pairs),
+            Err("Parameters are given as `name=value`, not `verbose`".to_string())
+        );
+    }
+
+    #[test]
+    fn plan_param_flag() {
+        let opts = PlanOpts::parse_args_default(&[
+            "upgrade.zplan",
+            "-p",
+            "version=1.2",
+            "--param",
+            "flags=-j4",
+        ])
+        .expect("Failed to parse");
+        assert_eq!(opts.parameter, vec!["version=1.2", "flags=-j4"]);
+    }
+
+    #[test]
+    fn keyscan_ssh_targets_only() {
+        let inventory: Inventory = r#"
+targets:
+  - name: web
+    uri: web.example.com
+  - name: www
+    uri: jails.example.com
+    config:
+      transport: jail
+      jail: www
+  - name: app
+    uri: app
+    config:
+      transport: docker
+  - name: here
+    uri: localhost
+groups: []
+config: {}
+"#
+        .parse()
+        .expect("Failed to parse the inventory");
+        let scanned: Vec<String> = inventory
+            .targets
+            .iter()
+            .filter(|target| reached_over_ssh(target))
+            .map(|target| target.name.clone())
+            .collect();
+        assert_eq!(scanned, vec!["web", "www"]);
+    }
+
+    #[test]
+    fn group_status_any_failure() {
+        let mut results = GroupResults::new();
+        results.insert("alpha".into(), exited(0));
+        assert_eq!(group_status(&results), 0);
+
+        results.insert("beta".into(), exited(2));
+        assert_eq!(group_status(&results), 1);
+
+        results.insert("beta".into(), Err(TransportError::NotConnected));
+        assert_eq!(group_status(&results), 1);
+    }
+}
@@ -0,0 +1,322 @@
+use colored::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use zap_model::events::{self, Summary};
+use zap_model::{Event, ExecutionResult, GroupResults};
+
+/**
+ * How zap presents what it is doing
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    /// Colored text for a person to read
+    #[default]
+    Human,
+    /// A single JSON document once everything is done
+    Json,
+    /// One JSON event per line, as they happen
+    Jsonl,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::Jsonl),
+            other => Err(format!(
+                "unknown format `{}`, expected human, json or jsonl",
+                other
+            )),
+        }
+    }
+}
+
+/**
+ * Output sends the events of a command wherever its format wants them, and
+ * keeps the results so that they can be summarized at the end
+ */
+pub struct Output {
+    format: Format,
+    /// Everything reported so far, only kept for the json format
+    events: Mutex<Vec<Event>>,
+    results: Mutex<Vec<ExecutionResult>>,
+    /// How many errors and checked files there were, and how many failed
+    others: Mutex<(usize, usize)>,
+}
+
+/**
+ * The json format's document
+ */
+#[derive(Serialize)]
+struct Document<'a> {
+    events: &'a [Event],
+    summary: Summary,
+}
+
+impl Output {
+    pub fn new(format: Format) -> Arc<Self> {
+        Arc::new(Self {
+            format,
+            events: Mutex::new(vec![]),
+            results: Mutex::new(vec![]),
+            others: Mutex::new((0, 0)),
+        })
+    }
+
+    pub fn is_human(&self) -> bool {
+        self.format == Format::Human
+    }
+
+    pub fn report(&self, event: &Event) {
+        let failed = match event {
+            Event::Result(result) => {
+                self.results.lock().unwrap().push(result.clone());
+                None
+            }
+            Event::Error { .. } => Some(true),
+            Event::Check { error, .. } => Some(error.is_some()),
+            _ => None,
+        };
+        if let Some(failed) = failed {
+            let mut others = self.others.lock().unwrap();
+            others.0 += 1;
+            others.1 += failed as usize;
+        }
+
+        match self.format {
+            Format::Human => events::print_human(event),
+            Format::Jsonl => println!("{}", to_json(event)),
+            Format::Json => self.events.lock().unwrap().push(event.clone()),
+        }
+    }
+
+    /**
+     * Report an error which isn't tied to any target
+     */
+    pub fn error(&self, error: String) {
+        self.report(&Event::Error {
+            task: None,
+            target: None,
+            error,
+        });
+    }
+
+    /**
+     * Every result reported so far
+     */
+    pub fn results(&self) -> Vec<ExecutionResult> {
+        self.results.lock().unwrap().clone()
+    }
+
+    /**
+     * Total up the results, errors and checks reported so far
+     */
+    pub fn summary(&self, exit_code: i32) -> Summary {
+        let results = self.results.lock().unwrap();
+        let (others, failed) = *self.others.lock().unwrap();
+        Summary {
+            exit_code,
+            total: results.len() + others,
+            failed: results.iter().filter(|r| !r.success()).count() + failed,
+            changed: results.iter().filter(|r| r.changed).count(),
+            skipped: results.iter().filter(|r| r.skipped).count(),
+        }
+    }
+
+    /**
+     * Finish off the output with the summary, and exit with its status
+     */
+    pub fn exit(&self, exit_code: i32) -> ! {
+        let summary = self.summary(exit_code);
+        match self.format {
+            Format::Human => {}
+            Format::Jsonl => println!("{}", to_json(&Event::Summary(summary))),
+            Format::Json => {
+                let events = self.events.lock().unwrap();
+                let document = Document {
+                    events: &events,
+                    summary,
+                };
+                match serde_json::to_string_pretty(&document) {
+                    Ok(json) => println!("{}", json),
+                    Err(err) => eprintln!("Failed to serialize the output: {}", err),
+                }
+            }
+        }
+        std::process::exit(exit_code);
+    }
+}
+
+/**
+ * Serialize the event onto a single line
+ */
+fn to_json(event: &Event) -> String {
+    serde_json::to_string(event).unwrap_or_else(|err| {
+        format!(
+            r#"{{"event":"error","error":"failed to serialize an event: {}"}}"#,
+            err
+        )
+    })
+}
+
+/**
+ * Print a table of how the task went on each target of a group
+ */
+pub fn print_summary(results: &GroupResults) {
+    let width = results
+        .keys()
+        .map(|name| name.len())
+        .chain(std::iter::once("Target".len()))
+        .max()
+        .unwrap_or_default();
+
+    println!("\n{:width$}  {:8}  Result", "Target", "Time", width = width);
+    for (name, outcome) in results.iter() {
+        let line = match outcome {
+            Ok(result) => {
+                let time = format!("{:.1}s", result.duration.as_secs_f64());
+                let line = format!(
+                    "{:width$}  {:8}  {}",
+                    name,
+                    time,
+                    describe(result),
+                    width = width
+                );
+                if result.success() {
+                    line.green()
+                } else {
+                    line.red()
+                }
+            }
+            Err(err) => format!("{:width$}  {:8}  {}", name, "-", err, width = width).red(),
+        };
+        println!("{}", line);
+    }
+
+    let failed = results
+        .values()
+        .filter(|o| !matches!(o, Ok(r) if r.success()))
+        .count();
+    if failed > 0 {
+        println!(
+            "{}",
+            format!("Failed on {} of {} target(s)", failed, results.len()).red()
+        );
+    }
+}
+
+/**
+ * A short description of how the task went on a target
+ */
+pub fn describe(result: &ExecutionResult) -> String {
+    let outcome = outcome(result);
+    if result.attempts > 1 {
+        format!("{} after {} attempts", outcome, result.attempts)
+    } else {
+        outcome
+    }
+}
+
+fn outcome(result: &ExecutionResult) -> String {
+    if result.timed_out {
+        "timed out".into()
+    } else if !result.success() {
+        format!("exited {}", result.exit_code)
+    } else if result.skipped {
+        "skipped".into()
+    } else if result.changed {
+        "changed".into()
+    } else {
+        "ok".into()
+    }
+}
+
+/**
+ * Print how every target fared over the course of a plan
+ */
+pub fn print_recap(results: &[ExecutionResult]) {
+    let mut targets: BTreeMap<&str, Vec<&ExecutionResult>> = BTreeMap::new();
+    for result in results.iter() {
+        targets.entry(&result.target).or_default().push(result);
+    }
+
+    println!("\nRecap");
+    for (target, results) in targets.iter() {
+        let changed = results.iter().filter(|r| r.changed).count();
+        let skipped = results.iter().filter(|r| r.skipped).count();
+        let failed = results.iter().filter(|r| !r.success()).count();
+        let duration: f64 = results.iter().map(|r| r.duration.as_secs_f64()).sum();
+        let line = format!(
+            "{}: {} changed, {} skipped, {} failed in {:.1}s",
+            target, changed, skipped, failed, duration
+        );
+        if failed > 0 {
+            println!("{}", line.red());
+        } else {
+            println!("{}", line.green());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_format() {
+        assert_eq!("jsonl".parse::<Format>(), Ok(Format::Jsonl));
+        assert!("yaml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn describe_results() {
+        let mut result = ExecutionResult {
+            changed: true,
+            ..Default::default()
+        };
+        assert_eq!(describe(&result), "changed");
+        result.changed = false;
+        result.skipped = true;
+        assert_eq!(describe(&result), "skipped");
+        result.exit_code = 3;
+        assert_eq!(describe(&result), "exited 3");
+        result.timed_out = true;
+        assert_eq!(describe(&result), "timed out");
+        result.attempts = 3;
+        assert_eq!(describe(&result), "timed out after 3 attempts");
+    }
+
+    #[test]
+    fn summarize() {
+        let output = Output::new(Format::Json);
+        output.report(&Event::Result(ExecutionResult {
+            changed: true,
+            ..Default::default()
+        }));
+        output.report(&Event::Result(ExecutionResult {
+            exit_code: 1,
+            ..Default::default()
+        }));
+        output.error("No target or group named `gamma`".into());
+        output.report(&Event::Check {
+            file: "echo.ztask".into(),
+            error: None,
+        });
+        assert_eq!(
+            output.summary(1),
+            Summary {
+                exit_code: 1,
+                total: 4,
+                failed: 2,
+                changed: 1,
+                skipped: 0,
+            }
+        );
+    }
+}
@@ -0,0 +1,442 @@
+use crate::transport::Stream;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/**
+ * The user commands are run as when escalation is enabled without a
+ * `become_user`
+ */
+const DEFAULT_USER: &str = "root";
+/// What doas and su print when asking for the become password
+const TTY_PROMPT: &str = "assword";
+/// What sudo is told to ask for the become password with, which no command
+/// is going to print by chance
+const SUDO_PROMPT: &str = "[zap] sudo password:";
+/// What a command run with a become password prints first, once the become
+/// method has let it run
+const STARTED: &str = "zap-escalated";
+
+/**
+ * The tool used to run commands as another user on the target
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BecomeMethod {
+    #[default]
+    Sudo,
+    Doas,
+    Su,
+}
+
+impl FromStr for BecomeMethod {
+    type Err = String;
+
+    fn from_str(method: &str) -> Result<Self, Self::Err> {
+        match method {
+            "sudo" => Ok(BecomeMethod::Sudo),
+            "doas" => Ok(BecomeMethod::Doas),
+            "su" => Ok(BecomeMethod::Su),
+            other => Err(format!(
+                "unknown become_method `{}`, expected one of sudo, doas or su",
+                other
+            )),
+        }
+    }
+}
+
+/**
+ * Escalation describes whether, and how, a task should be run as another user
+ * on the target.
+ *
+ * It can be set on tasks, plan steps, targets, groups and the inventory. Each
+ * setting is taken from the most specific place it is set, in that order.
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Escalation {
+    #[serde(rename = "become", skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    #[serde(rename = "become_method", skip_serializing_if = "Option::is_none")]
+    pub method: Option<BecomeMethod>,
+    #[serde(rename = "become_user", skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Only needed when the target doesn't allow escalating without one
+    #[serde(rename = "become_password", skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+impl Escalation {
+    /**
+     * Pull the `become`, `become_method` and `become_user` keys out of the
+     * given parameters, so they aren't passed along to the task itself
+     */
+    pub fn from_parameters(parameters: &mut HashMap<String, String>) -> Result<Self, String> {
+        let enabled = match parameters.remove("become").as_deref() {
+            Some("true") => Some(true),
+            Some("false") => Some(false),
+            Some(other) => {
+                return Err(format!(
+                    "become must be either true or false, not `{}`",
+                    other
+                ))
+            }
+            None => None,
+        };
+        let method = match parameters.remove("become_method") {
+            Some(method) => Some(method.parse()?),
+            None => None,
+        };
+
+        Ok(Self {
+            enabled,
+            method,
+            user: parameters.remove("become_user"),
+            password: None,
+        })
+    }
+
+    /**
+     * Fill in anything which isn't set here from `defaults`
+     */
+    pub fn merge(&self, defaults: &Escalation) -> Escalation {
+        Escalation {
+            enabled: self.enabled.or(defaults.enabled),
+            method: self.method.clone().or_else(|| defaults.method.clone()),
+            user: self.user.clone().or_else(|| defaults.user.clone()),
+            password: self.password.clone().or_else(|| defaults.password.clone()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(false)
+    }
+
+    pub fn method(&self) -> BecomeMethod {
+        self.method.clone().unwrap_or_default()
+    }
+
+    pub fn user(&self) -> &str {
+        self.user.as_deref().unwrap_or(DEFAULT_USER)
+    }
+
+    /**
+     * The password to provide to the become method, if escalating at all
+     */
+    pub fn password(&self) -> Option<&str> {
+        if self.is_enabled() {
+            self.password.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /**
+     * doas and su will only read a password from a terminal, so they need a
+     * pty and the password typed in at their prompt. sudo reads it from stdin.
+     */
+    pub fn prompts_on_tty(&self) -> bool {
+        self.password().is_some() && self.method() != BecomeMethod::Sudo
+    }
+
+    /**
+     * Watch the command's output for the become method asking for the
+     * password, if there is one to give it
+     */
+    pub(crate) fn password_prompt(&self) -> Option<PasswordPrompt> {
+        self.password().map(|_| PasswordPrompt {
+            tty: self.prompts_on_tty(),
+            started: false,
+            pending: String::new(),
+        })
+    }
+
+    /**
+     * Wrap the shell command so that it runs as the become user, or return it
+     * untouched when escalation isn't enabled.
+     *
+     * Without a password the command must not prompt at all, so that it fails
+     * rather than hanging forever waiting on input which will never come.
+     * With one, the command announces that it has started, see [PasswordPrompt].
+     */
+    pub fn wrap(&self, command: &str) -> String {
+        if !self.is_enabled() {
+            return command.to_string();
+        }
+        let user = shell_quote(self.user());
+        let has_password = self.password().is_some();
+        let announced = shell_quote(&format!("echo {} >&2; {}", STARTED, command));
+
+        match self.method() {
+            BecomeMethod::Sudo if has_password => format!(
+                "sudo -S -p {} -u {} -- /bin/sh -c {}",
+                shell_quote(SUDO_PROMPT),
+                user,
+                announced
+            ),
+            BecomeMethod::Sudo => format!("sudo -n -u {} -- {}", user, command),
+            BecomeMethod::Doas if has_password => {
+                format!("doas -u {} /bin/sh -c {}", user, announced)
+            }
+            BecomeMethod::Doas => format!("doas -n -u {} {}", user, command),
+            BecomeMethod::Su if has_password => format!("su {} -c {}", user, announced),
+            BecomeMethod::Su => format!("su {} -c {}", user, shell_quote(command)),
+        }
+    }
+}
+
+/**
+ * What the become method, once it has been given a password, has to say
+ */
+#[derive(Debug, PartialEq)]
+pub(crate) enum Prompted {
+    /// It is asking for the password
+    Asked,
+    /// It has let the command run, whether or not it asked first
+    Started,
+}
+
+/**
+ * PasswordPrompt follows what the become method says at the start of the
+ * command's output, so that the password is only sent once it is asked for
+ * and never ends up being read by the command itself.
+ *
+ * sudo asks on stderr with [SUDO_PROMPT], doas and su ask on the terminal. They
+ * may not ask at all, so the command announces itself with [STARTED] once
+ * it has been let run. The prompts and the announcement are kept out of the
+ * output, anything else is passed along a line at a time until then.
+ */
+#[derive(Debug)]
+pub(crate) struct PasswordPrompt {
+    tty: bool,
+    started: bool,
+    /// Output which hasn't yet made up a whole line, on the stream the become
+    /// method writes to
+    pending: String,
+}
+
+impl PasswordPrompt {
+    /**
+     * Look at the next of the command's output, passing along whatever isn't
+     * the become method's own, and return what it had to say if anything
+     */
+    pub(crate) fn watch(
+        &mut self,
+        stream: Stream,
+        text: &str,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Option<Prompted> {
+        if self.started || stream != self.stream() {
+            output(stream, text);
+            return None;
+        }
+        self.pending.push_str(text);
+
+        while let Some(end) = self.pending.find('\n') {
+            let line: String = self.pending.drain(..=end).collect();
+            if line.trim_end() == STARTED {
+                self.started = true;
+                self.finish(output);
+                return Some(Prompted::Started);
+            }
+            output(stream, &line);
+        }
+        if !self.is_prompt() {
+            return None;
+        }
+        self.pending.clear();
+        Some(Prompted::Asked)
+    }
+
+    /**
+     * Pass along anything still pending, once the output has ended
+     */
+    pub(crate) fn finish(&mut self, output: &mut dyn FnMut(Stream, &str)) {
+        if !self.pending.is_empty() {
+            output(self.stream(), &self.pending);
+            self.pending.clear();
+        }
+    }
+
+    fn stream(&self) -> Stream {
+        if self.tty {
+            Stream::Stdout
+        } else {
+            Stream::Stderr
+        }
+    }
+
+    /**
+     * Whether the pending line is the become method asking for the password,
+     * which it doesn't end with a newline since the answer goes there
+     */
+    fn is_prompt(&self) -> bool {
+        if !self.tty {
+            return self.pending.contains(SUDO_PROMPT);
+        }
+        // doas includes who is being asked, e.g. `doas (zap@host) password:`
+        match self.pending.rfind(TTY_PROMPT) {
+            Some(start) => self.pending[start..].trim_end().ends_with(':'),
+            None => false,
+        }
+    }
+}
+
+/**
+ * Quote the string for use as a single argument in a POSIX shell
+ */
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled(method: BecomeMethod, password: Option<&str>) -> Escalation {
+        Escalation {
+            enabled: Some(true),
+            method: Some(method),
+            user: None,
+            password: password.map(String::from),
+        }
+    }
+
+    #[test]
+    fn wrap_disabled() {
+        assert_eq!(Escalation::default().wrap("./cmd"), "./cmd");
+    }
+
+    #[test]
+    fn wrap_sudo() {
+        assert_eq!(
+            enabled(BecomeMethod::Sudo, None).wrap("./cmd"),
+            "sudo -n -u 'root' -- ./cmd"
+        );
+        assert_eq!(
+            enabled(BecomeMethod::Sudo, Some("pw")).wrap("./cmd"),
+            "sudo -S -p '[zap] sudo password:' -u 'root' -- \
+             /bin/sh -c 'echo zap-escalated >&2; ./cmd'"
+        );
+    }
+
+    fn watch(prompt: &mut PasswordPrompt, output: &[(Stream, &str)]) -> (Vec<Prompted>, String) {
+        let mut passed = String::new();
+        let mut prompted = vec![];
+        for (stream, text) in output {
+            prompted.extend(prompt.watch(*stream, text, &mut |_, text| passed.push_str(text)));
+        }
+        prompt.finish(&mut |_, text| passed.push_str(text));
+        (prompted, passed)
+    }
+
+    #[test]
+    fn sudo_password_prompt() {
+        let escalation = enabled(BecomeMethod::Sudo, Some("pw"));
+        let mut prompt = escalation.password_prompt().unwrap();
+        let watched = watch(
+            &mut prompt,
+            &[
+                (Stream::Stderr, "Be careful\n[zap] sudo "),
+                (Stream::Stderr, "password:"),
+                (Stream::Stderr, "zap-escalated\nzap-pid 42\n"),
+                (Stream::Stdout, "hello\n"),
+            ],
+        );
+        assert_eq!(
+            watched,
+            (
+                vec![Prompted::Asked, Prompted::Started],
+                "Be careful\nzap-pid 42\nhello\n".to_string()
+            )
+        );
+
+        // When no password is needed, the command mustn't be left waiting on
+        // one
+        let mut prompt = escalation.password_prompt().unwrap();
+        let watched = watch(&mut prompt, &[(Stream::Stderr, "zap-escalated\n")]);
+        assert_eq!(watched, (vec![Prompted::Started], String::new()));
+
+        let mut prompt = escalation.password_prompt().unwrap();
+        let watched = watch(&mut prompt, &[(Stream::Stderr, "sudo: unknown user")]);
+        assert_eq!(watched, (vec![], "sudo: unknown user".to_string()));
+
+        assert!(enabled(BecomeMethod::Sudo, None)
+            .password_prompt()
+            .is_none());
+    }
+
+    #[test]
+    fn tty_password_prompt() {
+        let escalation = enabled(BecomeMethod::Doas, Some("pw"));
+        let mut prompt = escalation.password_prompt().unwrap();
+        let watched = watch(
+            &mut prompt,
+            &[
+                (Stream::Stdout, "Last login\r\n"),
+                (Stream::Stdout, "doas (zap@host) pass"),
+                (Stream::Stdout, "word: "),
+                (Stream::Stdout, "\r\nzap-escalated\r\nhello\r\n"),
+            ],
+        );
+        assert_eq!(
+            watched,
+            (
+                vec![Prompted::Asked, Prompted::Started],
+                "Last login\r\n\r\nhello\r\n".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn wrap_doas() {
+        let mut escalation = enabled(BecomeMethod::Doas, None);
+        escalation.user = Some("www".into());
+        assert_eq!(escalation.wrap("./cmd"), "doas -n -u 'www' ./cmd");
+        assert!(!escalation.prompts_on_tty());
+    }
+
+    #[test]
+    fn wrap_su() {
+        let escalation = enabled(BecomeMethod::Su, Some("pw"));
+        assert_eq!(
+            escalation.wrap("./cmd 'args'"),
+            r"su 'root' -c 'echo zap-escalated >&2; ./cmd '\''args'\'''"
+        );
+        assert!(escalation.prompts_on_tty());
+    }
+
+    #[test]
+    fn merge_most_specific_wins() {
+        let task = Escalation {
+            enabled: Some(true),
+            ..Default::default()
+        };
+        let target = Escalation {
+            enabled: Some(false),
+            method: Some(BecomeMethod::Doas),
+            user: Some("www".into()),
+            password: None,
+        };
+        let merged = task.merge(&target);
+        assert!(merged.is_enabled());
+        assert_eq!(merged.method(), BecomeMethod::Doas);
+        assert_eq!(merged.user(), "www");
+    }
+
+    #[test]
+    fn from_parameters() {
+        let mut parameters = HashMap::new();
+        parameters.insert("become".to_string(), "true".to_string());
+        parameters.insert("become_method".to_string(), "su".to_string());
+        parameters.insert("packages".to_string(), "nginx".to_string());
+
+        let escalation = Escalation::from_parameters(&mut parameters).expect("Failed to parse");
+        assert!(escalation.is_enabled());
+        assert_eq!(escalation.method(), BecomeMethod::Su);
+        assert_eq!(parameters.len(), 1);
+
+        parameters.insert("become_method".to_string(), "runas".to_string());
+        assert!(Escalation::from_parameters(&mut parameters).is_err());
+    }
+}
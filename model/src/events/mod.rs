@@ -0,0 +1,209 @@
+use crate::runner::{as_seconds, ExecutionResult};
+use crate::transport::Stream;
+
+use chrono::{DateTime, Local};
+use colored::*;
+use serde::Serialize;
+use std::time::Duration;
+
+/**
+ * Events describe what is happening while zap runs, as it happens, so that
+ * they can be shown to a person or handed to other tooling.
+ *
+ * Each is serialized as an object with an `event` field naming the kind of
+ * event, alongside the fields of that kind. The fields of existing events are
+ * only ever added to, never renamed or removed.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A task is about to be run on the named target or group
+    TaskStart { task: String, targets: String },
+    /// The task is about to be run on a single target
+    TargetStart {
+        task: String,
+        target: String,
+        uri: String,
+    },
+    /// A line of output from the task on a target
+    Output {
+        task: String,
+        target: String,
+        stream: Stream,
+        text: String,
+        time: DateTime<Local>,
+    },
+    /// The script which would have been run on the target
+    DryRun {
+        task: String,
+        target: String,
+        script: String,
+    },
+    /// The task failed on a target, and is about to be run there again
+    Retry {
+        task: String,
+        target: String,
+        /// Which attempt failed, counting from 1
+        attempt: u32,
+        /// How many attempts there are in all
+        attempts: u32,
+        /// How the attempt failed
+        reason: String,
+        /// How long it will be until the next attempt
+        #[serde(serialize_with = "as_seconds")]
+        delay: Duration,
+    },
+    /// The task finished on a target
+    Result(ExecutionResult),
+    /// Something kept the task from running to completion
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        task: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        error: String,
+    },
+    /// A task or plan file was checked
+    Check {
+        file: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// Everything is done, this is always the last event
+    Summary(Summary),
+}
+
+/**
+ * The totals for a whole command
+ */
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Summary {
+    /// The status zap exits with
+    pub exit_code: i32,
+    /// How many results or checked files there were
+    pub total: usize,
+    pub failed: usize,
+    pub changed: usize,
+    pub skipped: usize,
+}
+
+/**
+ * Print the event for a person to read
+ *
+ * Summaries are left to the caller, which knows best how to present them.
+ */
+pub fn print_human(event: &Event) {
+    match event {
+        Event::TargetStart { target, uri, .. } => println!("Running on `{}` {}", target, uri),
+        Event::Output {
+            target,
+            stream,
+            text,
+            time,
+            ..
+        } => {
+            let prefix = format!(
+                "{} {}",
+                time.format("%H:%M:%S").to_string().dimmed(),
+                format!("[{}]", target).cyan()
+            );
+            match stream {
+                Stream::Stdout => println!("{} {}", prefix, text),
+                Stream::Stderr => println!("{} err: {}", prefix, text),
+            }
+        }
+        Event::DryRun { script, .. } => {
+            println!("{}", "Dry-run\n----".yellow());
+            print!("{}", script);
+            println!("{}", "\n----".yellow());
+        }
+        Event::Error {
+            target: Some(target),
+            error,
+            ..
+        } => println!("{}", format!("Failed on `{}`: {}", target, error).red()),
+        Event::Error { error, .. } => println!("{}", error.red()),
+        Event::Check { file, error: None } => println!("Checked {} properly", file),
+        Event::Check {
+            file,
+            error: Some(error),
+        } => println!("{}", format!("Failed to check {}: {}", file, error).red()),
+        Event::Retry {
+            target,
+            attempt,
+            attempts,
+            reason,
+            delay,
+            ..
+        } => println!(
+            "{}",
+            format!(
+                "Attempt {} of {} on `{}` failed ({}), retrying in {:.1}s",
+                attempt,
+                attempts,
+                target,
+                reason,
+                delay.as_secs_f64()
+            )
+            .yellow()
+        ),
+        Event::Result(result) if result.timed_out => println!(
+            "{}",
+            format!(
+                "Timed out on `{}` after {:.1}s",
+                result.target,
+                result.duration.as_secs_f64()
+            )
+            .red()
+        ),
+        Event::TaskStart { .. } | Event::Result(_) | Event::Summary(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn serialize_events() {
+        let event = Event::TargetStart {
+            task: "Echo".into(),
+            target: "alpha".into(),
+            uri: "192.168.1.1".into(),
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({"event": "target_start", "task": "Echo", "target": "alpha", "uri": "192.168.1.1"})
+        );
+
+        let event = Event::Error {
+            task: None,
+            target: Some("alpha".into()),
+            error: "not connected to the target".into(),
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({"event": "error", "target": "alpha", "error": "not connected to the target"})
+        );
+    }
+
+    #[test]
+    fn serialize_result() {
+        let event = Event::Result(ExecutionResult {
+            target: "alpha".into(),
+            task: "Echo".into(),
+            exit_code: 0,
+            stdout: "hi\n".into(),
+            duration: std::time::Duration::from_millis(1500),
+            changed: true,
+            ..Default::default()
+        });
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["event"], "result");
+        assert_eq!(value["stdout"], "hi\n");
+        assert_eq!(value["duration"], 1.5);
+        assert_eq!(value["changed"], true);
+        assert!(value["started"].is_string());
+    }
+}
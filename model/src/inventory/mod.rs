@@ -1,4 +1,21 @@
-use serde::{Deserialize, Serialize};
+use crate::escalation::Escalation;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use url::{Host, Url};
+
+/// The uri which selects the local transport without any config
+const LOCALHOST: &str = "localhost";
+/**
+ * The scheme assumed for target URIs which don't specify one, e.g. `192.168.1.1`
+ */
+const DEFAULT_SCHEME: &str = "ssh";
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Inventory {
@@ -7,10 +24,172 @@ pub struct Inventory {
     pub config: Config,
 }
 
+impl Inventory {
+    /**
+     * Load and validate the inventory at the given path
+     */
+    pub fn from_path(path: &Path) -> Result<Self, InventoryError> {
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| InventoryError::IoError(format!("{}: {}", path.display(), e)))?;
+        contents.parse()
+    }
+
+    /**
+     * Ensure that everything in the inventory which can be checked ahead of
+     * time is valid, so that mistakes are caught before connecting anywhere
+     */
+    pub fn validate(&self) -> Result<(), InventoryError> {
+        for target in self.targets.iter() {
+            target.parse_uri()?;
+            if let Some(config) = &target.config {
+                config.validate(&target.name)?;
+            }
+        }
+        for group in self.groups.iter() {
+            if let Some(config) = &group.config {
+                config.validate(&group.name)?;
+            }
+        }
+        self.config.validate("the inventory config")?;
+        Ok(())
+    }
+
+    /**
+     * Return the named target with the inventory-wide config applied beneath
+     * its own, so that anything the target doesn't set falls back to the
+     * inventory's settings
+     */
+    pub fn target(&self, name: &str) -> Option<Target> {
+        self.resolve_target(name, &self.config)
+    }
+
+    /**
+     * Return the named target with the group's config applied beneath its
+     * own, and the inventory-wide config beneath that
+     */
+    pub fn target_in_group(&self, name: &str, group: &Group) -> Option<Target> {
+        match &group.config {
+            Some(config) => self.resolve_target(name, &config.merge(&self.config)),
+            None => self.target(name),
+        }
+    }
+
+    /**
+     * Return every target in the groups whose names match the pattern, with
+     * their group's config applied, followed by the targets whose own names
+     * match it. Patterns may use `*` for any run of characters and `?` for a
+     * single one, and each target is only returned once.
+     */
+    pub fn select(&self, pattern: &str) -> Vec<Target> {
+        let mut selected: Vec<Target> = vec![];
+        let in_groups = self
+            .groups
+            .iter()
+            .filter(|group| glob_matches(pattern, &group.name))
+            .flat_map(|group| {
+                group
+                    .targets
+                    .iter()
+                    .filter_map(move |name| self.target_in_group(name, group))
+            });
+        let by_name = self
+            .targets
+            .iter()
+            .filter(|target| glob_matches(pattern, &target.name))
+            .filter_map(|target| self.target(&target.name));
+        for target in in_groups.chain(by_name) {
+            if !selected.iter().any(|t| t.name == target.name) {
+                selected.push(target);
+            }
+        }
+        selected
+    }
+
+    fn resolve_target(&self, name: &str, defaults: &Config) -> Option<Target> {
+        self.targets.iter().find(|t| t.name == name).map(|target| {
+            let mut target = target.clone();
+            target.config = Some(match &target.config {
+                Some(config) => config.merge(defaults),
+                None => defaults.clone(),
+            });
+            target
+        })
+    }
+}
+
+/**
+ * Whether the whole name matches the pattern, where `*` matches any run of
+ * characters and `?` any single character
+ */
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Where to pick up again when what followed the last `*` didn't match
+    let mut backtrack = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+impl FromStr for Inventory {
+    type Err = InventoryError;
+
+    fn from_str(buf: &str) -> Result<Self, Self::Err> {
+        let inventory: Inventory =
+            serde_yaml::from_str(buf).map_err(|e| InventoryError::ParseError(format!("{}", e)))?;
+        inventory.validate()?;
+        Ok(inventory)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum InventoryError {
+    IoError(String),
+    ParseError(String),
+    InvalidUri { target: String, reason: String },
+}
+
+impl fmt::Display for InventoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InventoryError::IoError(msg) => write!(f, "failed to read inventory: {}", msg),
+            InventoryError::ParseError(msg) => write!(f, "failed to parse inventory: {}", msg),
+            InventoryError::InvalidUri { target, reason } => {
+                write!(f, "target `{}` has an invalid uri: {}", target, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InventoryError {}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Group {
     pub name: String,
     pub targets: Vec<String>,
+    /// Config for the group's targets, beneath their own but above the inventory's
+    pub config: Option<Config>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -20,32 +199,405 @@ pub struct Target {
     pub config: Option<Config>,
 }
 
+impl Target {
+    /**
+     * Parse the target's uri into its component parts.
+     *
+     * The uri may be as simple as a hostname or IP address, or include any of
+     * the scheme, user and port, e.g. `ssh://root@192.168.1.1:2222`. IPv6
+     * addresses may be given bare (`fe80::1`) when no port is needed, or
+     * bracketed (`[fe80::1]:2222`) otherwise.
+     */
+    pub fn parse_uri(&self) -> Result<TargetUri, InventoryError> {
+        self.uri
+            .parse()
+            .map_err(|reason| InventoryError::InvalidUri {
+                target: self.name.clone(),
+                reason,
+            })
+    }
+
+    /**
+     * The transport to reach the target with. A target whose uri is just
+     * `localhost` is run on directly unless another transport is configured,
+     * use `ssh://localhost` to connect to it over ssh instead.
+     */
+    pub fn transport(&self) -> Transport {
+        let transport = self
+            .config
+            .as_ref()
+            .and_then(|c| c.transport.clone())
+            .unwrap_or_else(default_transport);
+        self.localhost_shortcut(transport)
+    }
+
+    /**
+     * The transport which the `jail` and `chroot` transports reach the
+     * target's host with
+     */
+    pub fn via(&self) -> Transport {
+        let via = self
+            .config
+            .as_ref()
+            .and_then(|c| c.via.clone())
+            .filter(|via| !via.is_wrapper())
+            .unwrap_or_else(default_transport);
+        self.localhost_shortcut(via)
+    }
+
+    fn localhost_shortcut(&self, transport: Transport) -> Transport {
+        if transport == Transport::Ssh && self.uri == LOCALHOST {
+            Transport::Local
+        } else {
+            transport
+        }
+    }
+}
+
+/**
+ * TargetUri is the parsed form of a Target's `uri`
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct TargetUri {
+    pub scheme: String,
+    pub user: Option<String>,
+    /// IPv6 addresses are stored without their surrounding brackets
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl FromStr for TargetUri {
+    type Err = String;
+
+    /**
+     * The uri may be as simple as a hostname or IP address, or include any of
+     * the scheme, user and port, e.g. `ssh://root@192.168.1.1:2222`. IPv6
+     * addresses may be given bare (`fe80::1`) when no port is needed, or
+     * bracketed (`[fe80::1]:2222`) otherwise.
+     */
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let uri = raw.trim();
+        let uri = if uri.contains("://") {
+            uri.to_string()
+        } else if uri.matches(':').count() > 1 && !uri.contains('[') {
+            // A bare IPv6 address, which needs brackets to be parsed as a host
+            let (user, host) = match uri.rfind('@') {
+                Some(idx) => (&uri[..=idx], &uri[idx + 1..]),
+                None => ("", uri),
+            };
+            format!("{}://{}[{}]", DEFAULT_SCHEME, user, host)
+        } else {
+            format!("{}://{}", DEFAULT_SCHEME, uri)
+        };
+
+        let url = Url::parse(&uri).map_err(|e| format!("{} ({})", e, raw))?;
+
+        let host = match url.host() {
+            Some(Host::Domain(domain)) if !domain.is_empty() => domain.to_string(),
+            Some(Host::Ipv4(addr)) => addr.to_string(),
+            Some(Host::Ipv6(addr)) => addr.to_string(),
+            _ => return Err(format!("no host found in `{}`", raw)),
+        };
+
+        if !url.path().is_empty() && url.path() != "/" {
+            return Err(format!("unexpected path `{}` in `{}`", url.path(), raw));
+        }
+
+        let user = match url.username() {
+            "" => None,
+            user => Some(user.to_string()),
+        };
+
+        Ok(TargetUri {
+            scheme: url.scheme().to_string(),
+            user,
+            host,
+            port: url.port(),
+        })
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
-    #[serde(default = "default_transport")]
-    pub transport: Transport,
+    /// How the target is reached, defaults to ssh
+    pub transport: Option<Transport>,
     pub ssh: Option<SshConfig>,
+    pub docker: Option<DockerConfig>,
+    /// The jail to run in with the `jail` transport
+    pub jail: Option<String>,
+    /// The directory to chroot into with the `chroot` transport
+    pub chroot: Option<String>,
+    /// How the `jail` and `chroot` transports reach the host, defaults to ssh
+    pub via: Option<Transport>,
+    /// How many targets of a group to run on at once, only meaningful in the
+    /// inventory-wide config
+    pub parallel: Option<usize>,
+    /// Where on the target each run's private working directory is created,
+    /// defaults to `$TMPDIR` or else `/tmp`
+    pub tmpdir: Option<String>,
+    /// Values for the variables of plans run on the target
+    #[serde(default, deserialize_with = "scalars")]
+    pub vars: HashMap<String, String>,
+    /// `become`, `become_method`, `become_user` and `become_password`
+    #[serde(flatten)]
+    pub escalation: Escalation,
 }
 fn default_transport() -> Transport {
     Transport::Ssh
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/**
+ * Vars are written however is natural in YAML, e.g. `version: 1.2`, but are
+ * always used as strings
+ */
+fn scalars<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let vars: HashMap<String, serde_yaml::Value> = HashMap::deserialize(deserializer)?;
+    vars.into_iter()
+        .map(|(name, value)| {
+            let value = match value {
+                serde_yaml::Value::String(value) => value,
+                serde_yaml::Value::Number(value) => value.to_string(),
+                serde_yaml::Value::Bool(value) => value.to_string(),
+                _ => {
+                    return Err(D::Error::custom(format!(
+                        "the var `{}` must be a string, number or boolean",
+                        name
+                    )))
+                }
+            };
+            Ok((name, value))
+        })
+        .collect()
+}
+
+impl Config {
+    fn validate(&self, name: &str) -> Result<(), InventoryError> {
+        if let Some(via) = &self.via {
+            if via.is_wrapper() {
+                return Err(InventoryError::ParseError(format!(
+                    "{}: `via` must reach a host directly, it cannot be a jail or chroot",
+                    name
+                )));
+            }
+        }
+        if let Some(proxy_jump) = self.ssh.as_ref().and_then(|s| s.proxy_jump.as_ref()) {
+            for hop in proxy_jump.hops() {
+                hop.uri
+                    .parse::<TargetUri>()
+                    .map_err(|reason| InventoryError::InvalidUri {
+                        target: format!("{} (proxy_jump)", name),
+                        reason,
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Fill in anything which isn't set in this config from `defaults`
+     */
+    pub fn merge(&self, defaults: &Config) -> Config {
+        let ssh = match (&self.ssh, &defaults.ssh) {
+            (Some(ssh), Some(default_ssh)) => Some(ssh.merge(default_ssh)),
+            (ssh, default_ssh) => ssh.clone().or_else(|| default_ssh.clone()),
+        };
+        let docker = match (&self.docker, &defaults.docker) {
+            (Some(docker), Some(default_docker)) => Some(docker.merge(default_docker)),
+            (docker, default_docker) => docker.clone().or_else(|| default_docker.clone()),
+        };
+        Config {
+            transport: self
+                .transport
+                .clone()
+                .or_else(|| defaults.transport.clone()),
+            ssh,
+            docker,
+            jail: self.jail.clone().or_else(|| defaults.jail.clone()),
+            chroot: self.chroot.clone().or_else(|| defaults.chroot.clone()),
+            via: self.via.clone().or_else(|| defaults.via.clone()),
+            parallel: self.parallel.or(defaults.parallel),
+            tmpdir: self.tmpdir.clone().or_else(|| defaults.tmpdir.clone()),
+            vars: defaults
+                .vars
+                .iter()
+                .chain(self.vars.iter())
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            escalation: self.escalation.merge(&defaults.escalation),
+        }
+    }
+}
+
+/**
+ * DockerConfig is used by targets which are containers, reached with the
+ * `docker` transport
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DockerConfig {
+    /// The name or ID of the container, defaults to the host of the target's uri
+    pub container: Option<String>,
+    /// The user to run commands as inside the container
+    pub user: Option<String>,
+}
+
+impl DockerConfig {
+    /**
+     * Fill in anything which isn't set in this config from `defaults`
+     */
+    pub fn merge(&self, defaults: &DockerConfig) -> DockerConfig {
+        DockerConfig {
+            container: self
+                .container
+                .clone()
+                .or_else(|| defaults.container.clone()),
+            user: self.user.clone().or_else(|| defaults.user.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct SshConfig {
-    pub user: String,
+    /// The user to log in as, unless one is given in the target's uri
+    pub user: Option<String>,
+    /// The port to connect to, unless one is given in the target's uri
+    pub port: Option<u16>,
     pub password: Option<String>,
     pub privatekey_path: Option<String>,
+    /// How to treat the target's host key, defaults to `strict`
+    pub host_key_policy: Option<HostKeyPolicy>,
+    /// The known_hosts file to verify host keys with, defaults to ~/.ssh/known_hosts
+    pub known_hosts: Option<String>,
+    /// Jump host(s) to tunnel the connection through
+    pub proxy_jump: Option<ProxyJump>,
+    /// How many seconds connecting, the handshake and authenticating may
+    /// each take before giving up on the target
+    pub connect_timeout: Option<u64>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+impl SshConfig {
+    /**
+     * Fill in anything which isn't set in this config from `defaults`
+     */
+    pub fn merge(&self, defaults: &SshConfig) -> SshConfig {
+        SshConfig {
+            user: self.user.clone().or_else(|| defaults.user.clone()),
+            port: self.port.or(defaults.port),
+            password: self.password.clone().or_else(|| defaults.password.clone()),
+            privatekey_path: self
+                .privatekey_path
+                .clone()
+                .or_else(|| defaults.privatekey_path.clone()),
+            host_key_policy: self
+                .host_key_policy
+                .clone()
+                .or_else(|| defaults.host_key_policy.clone()),
+            known_hosts: self
+                .known_hosts
+                .clone()
+                .or_else(|| defaults.known_hosts.clone()),
+            proxy_jump: self
+                .proxy_jump
+                .clone()
+                .or_else(|| defaults.proxy_jump.clone()),
+            connect_timeout: self.connect_timeout.or(defaults.connect_timeout),
+        }
+    }
+}
+
+/**
+ * ProxyJump is the chain of jump hosts a connection is tunnelled through, in
+ * the order they are connected to.
+ *
+ * It may be given like ssh's `-J` flag, e.g. `bastion,jumper@inner:2222`, or
+ * as a list of hops for when they each need their own credentials
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum ProxyJump {
+    Chain(String),
+    Hops(Vec<JumpHost>),
+}
+
+impl ProxyJump {
+    pub fn hops(&self) -> Vec<JumpHost> {
+        match self {
+            ProxyJump::Chain(chain) => chain
+                .split(',')
+                .map(str::trim)
+                .filter(|hop| !hop.is_empty())
+                .map(|hop| JumpHost {
+                    uri: hop.to_string(),
+                    password: None,
+                    privatekey_path: None,
+                })
+                .collect(),
+            ProxyJump::Hops(hops) => hops.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct JumpHost {
+    pub uri: String,
+    pub password: Option<String>,
+    pub privatekey_path: Option<String>,
+}
+
+/**
+ * HostKeyPolicy determines what happens when a target's host key is checked
+ * against the known_hosts file
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyPolicy {
+    /// Refuse to connect to any host whose key is missing or different
+    #[default]
+    Strict,
+    /// Record the keys of hosts not yet known, but refuse any which changed
+    AcceptNew,
+    /// Skip host key verification entirely
+    Off,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub enum Transport {
     Ssh,
+    /// Run on the machine zap itself is running on
+    Local,
+    /// Run inside a container with the docker CLI
+    Docker,
+    /// Run inside a FreeBSD jail on a host reached `via` another transport
+    Jail,
+    /// Run inside a chroot on a host reached `via` another transport
+    Chroot,
+}
+
+impl Transport {
+    /**
+     * Whether the transport runs inside something on a host reached by
+     * another transport, rather than reaching a host itself
+     */
+    pub fn is_wrapper(&self) -> bool {
+        matches!(self, Transport::Jail | Transport::Chroot)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::escalation::BecomeMethod;
+
+    fn target(uri: &str) -> Target {
+        Target {
+            name: "test".into(),
+            uri: uri.into(),
+            config: None,
+        }
+    }
 
     #[test]
     fn deserialize_with_transport() {
@@ -55,6 +607,327 @@ targets: []
 groups: []
 config:
   transport: ssh"#;
-        let _i: Inventory = serde_yaml::from_str(&buf).expect("Failed to deser");
+        let _i: Inventory = serde_yaml::from_str(buf).expect("Failed to deser");
+    }
+
+    #[test]
+    fn parse_bare_host_uri() {
+        let uri = target("192.168.1.1").parse_uri().expect("Failed to parse");
+        assert_eq!(uri.scheme, "ssh");
+        assert_eq!(uri.host, "192.168.1.1");
+        assert_eq!(uri.user, None);
+        assert_eq!(uri.port, None);
+    }
+
+    #[test]
+    fn parse_full_uri() {
+        let uri = target("ssh://root@example.com:2222")
+            .parse_uri()
+            .expect("Failed to parse");
+        assert_eq!(uri.host, "example.com");
+        assert_eq!(uri.user, Some("root".into()));
+        assert_eq!(uri.port, Some(2222));
+    }
+
+    #[test]
+    fn parse_user_host_port_uri() {
+        let uri = target("root@gopher:2222")
+            .parse_uri()
+            .expect("Failed to parse");
+        assert_eq!(uri.scheme, "ssh");
+        assert_eq!(uri.host, "gopher");
+        assert_eq!(uri.user, Some("root".into()));
+        assert_eq!(uri.port, Some(2222));
+    }
+
+    #[test]
+    fn parse_ipv6_uris() {
+        let uri = target("fe80::1").parse_uri().expect("Failed to parse");
+        assert_eq!(uri.host, "fe80::1");
+        assert_eq!(uri.port, None);
+
+        let uri = target("root@[fe80::1]:2222")
+            .parse_uri()
+            .expect("Failed to parse");
+        assert_eq!(uri.host, "fe80::1");
+        assert_eq!(uri.user, Some("root".into()));
+        assert_eq!(uri.port, Some(2222));
+    }
+
+    #[test]
+    fn parse_invalid_uris() {
+        assert!(target("host:notaport").parse_uri().is_err());
+        assert!(target("ssh://").parse_uri().is_err());
+        assert!(target("host/with/path").parse_uri().is_err());
+    }
+
+    #[test]
+    fn target_inherits_inventory_config() {
+        let buf = r#"
+---
+targets:
+  - name: alpha
+    uri: 192.168.1.1
+    config:
+      ssh:
+        user: root
+  - name: beta
+    uri: 192.168.1.2
+groups: []
+config:
+  transport: ssh
+  tmpdir: /var/tmp
+  ssh:
+    user: zap
+    host_key_policy: accept-new
+    known_hosts: /etc/zap/known_hosts"#;
+        let inventory: Inventory = buf.parse().expect("Failed to parse");
+
+        let alpha = inventory.target("alpha").expect("Failed to find alpha");
+        let config = alpha.config.unwrap();
+        assert_eq!(config.tmpdir, Some("/var/tmp".into()));
+        let ssh = config.ssh.unwrap();
+        assert_eq!(ssh.user, Some("root".into()));
+        assert_eq!(ssh.host_key_policy, Some(HostKeyPolicy::AcceptNew));
+        assert_eq!(ssh.known_hosts, Some("/etc/zap/known_hosts".into()));
+
+        let beta = inventory.target("beta").expect("Failed to find beta");
+        assert_eq!(beta.config.unwrap().ssh.unwrap().user, Some("zap".into()));
+
+        assert!(inventory.target("gamma").is_none());
+    }
+
+    #[test]
+    fn proxy_jump_chain() {
+        let jump = ProxyJump::Chain("bastion, jumper@inner:2222".into());
+        let hops = jump.hops();
+        assert_eq!(hops.len(), 2);
+        assert_eq!(hops[0].uri, "bastion");
+        assert_eq!(hops[1].uri, "jumper@inner:2222");
+    }
+
+    #[test]
+    fn deserialize_proxy_jump_hops() {
+        let buf = r#"
+---
+targets:
+  - name: db
+    uri: 10.0.0.5
+    config:
+      ssh:
+        proxy_jump:
+          - uri: jumper@bastion.example.com
+            privatekey_path: /keys/bastion
+          - uri: inner
+            password: hunter2
+groups: []
+config:
+  transport: ssh
+  ssh:
+    proxy_jump: bastion.example.com"#;
+        let inventory: Inventory = buf.parse().expect("Failed to parse");
+        let ssh = inventory.target("db").unwrap().config.unwrap().ssh.unwrap();
+        let hops = ssh.proxy_jump.unwrap().hops();
+        assert_eq!(hops.len(), 2);
+        assert_eq!(hops[0].privatekey_path, Some("/keys/bastion".into()));
+        assert_eq!(hops[1].password, Some("hunter2".into()));
+    }
+
+    #[test]
+    fn inventory_with_invalid_proxy_jump() {
+        let buf = r#"
+---
+targets: []
+groups: []
+config:
+  transport: ssh
+  ssh:
+    proxy_jump: 'bastion,host:notaport'"#;
+        assert!(buf.parse::<Inventory>().is_err());
+    }
+
+    #[test]
+    fn group_config_escalation() {
+        let buf = r#"
+---
+targets:
+  - name: alpha
+    uri: 192.168.1.1
+    config:
+      become_user: www
+  - name: beta
+    uri: 192.168.1.2
+groups:
+  - name: bsd
+    targets:
+      - alpha
+      - beta
+    config:
+      become: true
+      become_method: doas
+config:
+  transport: ssh
+  become_method: sudo
+  become_password: hunter2"#;
+        let inventory: Inventory = buf.parse().expect("Failed to parse");
+        let group = &inventory.groups[0];
+
+        let alpha = inventory.target_in_group("alpha", group).unwrap();
+        let escalation = alpha.config.unwrap().escalation;
+        assert!(escalation.is_enabled());
+        assert_eq!(escalation.method(), BecomeMethod::Doas);
+        assert_eq!(escalation.user(), "www");
+        assert_eq!(escalation.password, Some("hunter2".into()));
+
+        let beta = inventory.target("beta").unwrap();
+        let escalation = beta.config.unwrap().escalation;
+        assert!(!escalation.is_enabled());
+        assert_eq!(escalation.method(), BecomeMethod::Sudo);
+    }
+
+    #[test]
+    fn merge_vars() {
+        let buf = r#"
+---
+targets:
+  - name: alpha
+    uri: 192.168.1.1
+    config:
+      vars:
+        port: 8080
+groups:
+  - name: web
+    targets: [alpha]
+    config:
+      vars:
+        port: 80
+        tls: true
+config:
+  transport: ssh
+  vars:
+    datacenter: fra1"#;
+        let inventory: Inventory = buf.parse().expect("Failed to parse");
+        let alpha = inventory
+            .target_in_group("alpha", &inventory.groups[0])
+            .unwrap();
+        let vars = alpha.config.unwrap().vars;
+        assert_eq!(vars["port"], "8080");
+        assert_eq!(vars["tls"], "true");
+        assert_eq!(vars["datacenter"], "fra1");
+
+        let buf = r#"
+---
+targets: []
+groups: []
+config:
+  transport: ssh
+  vars:
+    packages: [nginx]"#;
+        assert!(buf.parse::<Inventory>().is_err());
+    }
+
+    #[test]
+    fn select_targets() {
+        let buf = r#"
+---
+targets:
+  - name: web-1
+    uri: 192.168.1.1
+  - name: web-2
+    uri: 192.168.1.2
+  - name: db-1
+    uri: 192.168.1.3
+groups:
+  - name: db
+    targets: [db-1]
+    config:
+      become: true
+config:
+  transport: ssh"#;
+        let inventory: Inventory = buf.parse().expect("Failed to parse");
+        let names = |pattern| -> Vec<String> {
+            inventory
+                .select(pattern)
+                .into_iter()
+                .map(|target| target.name)
+                .collect()
+        };
+        assert_eq!(names("web-*"), vec!["web-1", "web-2"]);
+        assert_eq!(names("db*"), vec!["db-1"]);
+        assert_eq!(names("web-?"), vec!["web-1", "web-2"]);
+        assert_eq!(names("*"), vec!["db-1", "web-1", "web-2"]);
+        assert!(names("lb").is_empty());
+
+        // Targets selected through a group get its config
+        let db = inventory.select("db").remove(0);
+        assert!(db.config.unwrap().escalation.is_enabled());
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_matches("web", "web"));
+        assert!(!glob_matches("web", "web-1"));
+        assert!(glob_matches("*-1", "web-1"));
+        assert!(glob_matches("w*b*1", "web-web-1"));
+        assert!(!glob_matches("w*b*2", "web-web-1"));
+        assert!(glob_matches("**", ""));
+        assert!(!glob_matches("?", ""));
+    }
+
+    #[test]
+    fn inventory_with_invalid_uri() {
+        let buf = r#"
+---
+targets:
+  - name: broken
+    uri: 'host:99999'
+groups: []
+config:
+  transport: ssh"#;
+        match buf.parse::<Inventory>() {
+            Err(InventoryError::InvalidUri { target, .. }) => assert_eq!(target, "broken"),
+            other => panic!("Expected an InvalidUri error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deserialize_parallel() {
+        let buf = r#"
+---
+targets: []
+groups: []
+config:
+  parallel: 10"#;
+        let inventory: Inventory = buf.parse().expect("Failed to parse");
+        assert_eq!(inventory.config.parallel, Some(10));
+    }
+
+    #[test]
+    fn localhost_uses_local_transport() {
+        assert_eq!(target("localhost").transport(), Transport::Local);
+        assert_eq!(target("ssh://localhost").transport(), Transport::Ssh);
+
+        let mut chroot = target("localhost");
+        chroot.config = Some(serde_yaml::from_str("transport: chroot").unwrap());
+        assert_eq!(chroot.transport(), Transport::Chroot);
+        assert_eq!(chroot.via(), Transport::Local);
+    }
+
+    #[test]
+    fn transport_from_inventory_config() {
+        let buf = r#"
+---
+targets:
+  - name: web
+    uri: docker://web
+    config:
+      vars:
+        port: 80
+groups: []
+config:
+  transport: docker"#;
+        let inventory: Inventory = buf.parse().expect("Failed to parse");
+        let web = inventory.target("web").unwrap();
+        assert_eq!(web.transport(), Transport::Docker);
     }
 }
@@ -1,21 +1,34 @@
-#[macro_use]
 extern crate pest;
 #[macro_use]
 extern crate pest_derive;
 
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::inventory::Target;
+use crate::plan::Variables;
 
+pub mod escalation;
+pub mod events;
 pub mod inventory;
 pub mod plan;
+pub mod runner;
 pub mod task;
 pub mod tasks;
 pub mod transport;
 
+pub use crate::escalation::Escalation;
+pub use crate::events::Event;
 pub use crate::plan::Plan;
+pub use crate::runner::{ExecutionResult, GroupResults, Runner};
 pub use crate::task::Task;
 pub use crate::transport::{Transport, TransportError};
 
+/// How long to wait before retrying a task which failed, unless told otherwise
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
 /**
  * An ExecutableTask is a light container over a Task execpt with user-provided information and is
  * therefore ready for execution
@@ -24,11 +37,88 @@ pub use crate::transport::{Transport, TransportError};
 pub struct ExecutableTask {
     pub task: Task,
     pub parameters: HashMap<String, String>,
+    /// Escalation settings given alongside the parameters, e.g. in a plan step
+    pub escalation: Escalation,
+    /// How long the task may run for, overriding the task's own timeout
+    pub timeout: Option<Duration>,
+    /// How many more times the task is run on a target where it failed
+    pub retries: u32,
+    /// How long to wait before the first retry, doubling with each retry after
+    pub retry_delay: Duration,
+    /// A script which must succeed as well for the task to have succeeded
+    pub until: Option<String>,
+    /// A failure doesn't stop the rest of the plan from running on the target
+    pub ignore_errors: bool,
+    /// The groups or targets to run on, by name or pattern, rather than all
+    /// of those the plan is run on
+    pub on: Vec<String>,
+    /// What the placeholders in the parameters are filled in with, for the
+    /// tasks of a plan
+    pub variables: Option<Arc<Variables>>,
 }
 
 impl ExecutableTask {
     pub fn new(task: Task, parameters: HashMap<String, String>) -> Self {
-        Self { task, parameters }
+        Self {
+            task,
+            parameters,
+            escalation: Escalation::default(),
+            timeout: None,
+            retries: 0,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            until: None,
+            ignore_errors: false,
+            on: vec![],
+            variables: None,
+        }
+    }
+
+    /**
+     * How long the task may run for on a target, if there's a limit at all
+     */
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.or(self.task.timeout)
+    }
+
+    /**
+     * How long to wait after the given attempt failed before trying again
+     */
+    pub fn retry_delay(&self, attempt: u32) -> Duration {
+        self.retry_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    }
+
+    /**
+     * The task with the placeholders in its parameters and `until` filled in
+     * for the target, if it's part of a plan
+     */
+    pub fn for_target(&self, target: &Target) -> Result<ExecutableTask, String> {
+        let variables = match &self.variables {
+            Some(variables) => variables,
+            None => return Ok(self.clone()),
+        };
+        let values = variables.resolve(target)?;
+        let mut task = self.clone();
+        for value in task.parameters.values_mut() {
+            *value = plan::render(value, &values)?;
+        }
+        if let Some(until) = &mut task.until {
+            *until = plan::render(until, &values)?;
+        }
+        Ok(task)
+    }
+
+    /**
+     * Determine how this task should escalate privileges on the given target,
+     * taking the most specific setting from this task, its definition and
+     * then the target's config
+     */
+    pub fn escalation_for(&self, target: &Target) -> Escalation {
+        let mut escalation = self.escalation.merge(&self.task.escalation);
+        if let Some(config) = &target.config {
+            escalation = escalation.merge(&config.escalation);
+        }
+        escalation
     }
 
     /**
@@ -42,4 +132,15 @@ impl ExecutableTask {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_backs_off() {
+        let mut task = ExecutableTask::new(Task::new("Flaky"), HashMap::new());
+        task.retry_delay = Duration::from_secs(10);
+        assert_eq!(task.retry_delay(1), Duration::from_secs(10));
+        assert_eq!(task.retry_delay(3), Duration::from_secs(40));
+        assert!(task.retry_delay(64) > Duration::from_secs(60 * 60 * 24 * 365));
+    }
+}
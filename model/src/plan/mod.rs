@@ -1,3 +1,4 @@
+use handlebars::Handlebars;
 use log::*;
 use pest::error::Error as PestError;
 use pest::error::ErrorVariant;
@@ -5,8 +6,11 @@ use pest::iterators::Pairs;
 use pest::Parser;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::{ExecutableTask, Task};
+use crate::inventory::{Inventory, Target};
+use crate::{Escalation, ExecutableTask, Task};
 
 #[derive(Parser)]
 #[grammar = "plan.pest"]
@@ -15,22 +19,147 @@ struct PlanParser;
 #[derive(Clone, Debug)]
 pub struct Plan {
     pub tasks: Vec<ExecutableTask>,
+    /// What the placeholders in the tasks are filled in with
+    pub variables: Variables,
 }
 
+/**
+ * A parameter of the plan, which is declared like those of a task
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Parameter {
+    pub name: String,
+    pub required: bool,
+    /// The value used when none is given on the command line or by the target
+    pub default: Option<String>,
+    pub help: Option<String>,
+}
+
+/**
+ * Variables are what the `{{name}}` placeholders in the arguments of a plan's
+ * tasks are filled in with, which can differ from one target to the next.
+ *
+ * A parameter takes the value given on the command line, or else the one in
+ * the target's `vars`, or else its default. `let` variables are then worked
+ * out from those, in the order they were defined.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Variables {
+    pub parameters: Vec<Parameter>,
+    /// Values given for the parameters, e.g. with `-p` on the command line
+    pub given: HashMap<String, String>,
+    /// Each `let` variable's name and template
+    pub lets: Vec<(String, String)>,
+}
+
+impl Variables {
+    /**
+     * Work out the value of every variable on the target
+     */
+    pub fn resolve(&self, target: &Target) -> Result<HashMap<String, String>, String> {
+        let mut values = HashMap::new();
+        for parameter in self.parameters.iter() {
+            match (&parameter.default, parameter.required) {
+                (Some(default), _) => {
+                    values.insert(parameter.name.clone(), default.clone());
+                }
+                // Optional parameters can be left out altogether
+                (None, false) => {
+                    values.insert(parameter.name.clone(), String::new());
+                }
+                (None, true) => {}
+            }
+        }
+        if let Some(config) = &target.config {
+            values.extend(config.vars.clone());
+        }
+        values.extend(self.given.clone());
+
+        for parameter in self.parameters.iter().filter(|p| p.required) {
+            if !values.contains_key(&parameter.name) {
+                return Err(format!(
+                    "the plan needs a value for `{}`, e.g. with `-p {}=...`",
+                    parameter.name, parameter.name
+                ));
+            }
+        }
+        for (name, template) in self.lets.iter() {
+            let value = render(template, &values)?;
+            values.insert(name.clone(), value);
+        }
+        Ok(values)
+    }
+}
+
+/**
+ * Fill in the `{{name}}` placeholders in the template, a placeholder without
+ * a value is an error rather than being left empty
+ */
+pub fn render(template: &str, values: &HashMap<String, String>) -> Result<String, String> {
+    if !template.contains("{{") {
+        return Ok(template.to_string());
+    }
+    let mut hb = Handlebars::new();
+    hb.register_escape_fn(handlebars::no_escape);
+    hb.set_strict_mode(true);
+    hb.render_template(template, values)
+        .map_err(|e| format!("failed to fill in `{}`: {}", template, e))
+}
+
+impl Default for Plan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(clippy::result_large_err)]
 impl Plan {
     pub fn new() -> Self {
-        Self { tasks: vec![] }
+        Self {
+            tasks: vec![],
+            variables: Variables::default(),
+        }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(buf: &str) -> Result<Self, PestError<Rule>> {
-        let mut parser = PlanParser::parse(Rule::planfile, buf)?;
+        let parser = PlanParser::parse(Rule::planfile, buf)?;
         let mut plan = Plan::new();
 
-        while let Some(parsed) = parser.next() {
+        for parsed in parser {
             match parsed.as_rule() {
+                Rule::parameters => {
+                    for pair in parsed.into_inner() {
+                        let parameter = parse_parameter(&mut pair.into_inner())?;
+                        plan.variables.parameters.push(parameter);
+                    }
+                }
+                Rule::variable => {
+                    let span = parsed.as_span();
+                    let mut pairs = parsed.into_inner();
+                    let name = pairs
+                        .next()
+                        .map(|p| p.as_str().to_string())
+                        .unwrap_or_default();
+                    let template = parse_str(&mut pairs)?;
+                    let variables = &plan.variables;
+                    if variables.parameters.iter().any(|p| p.name == name)
+                        || variables.lets.iter().any(|(n, _)| *n == name)
+                    {
+                        return Err(PestError::new_from_span(
+                            ErrorVariant::CustomError {
+                                message: format!("`{}` has already been defined", name),
+                            },
+                            span,
+                        ));
+                    }
+                    plan.variables.lets.push((name, template));
+                }
                 Rule::task => {
+                    let span = parsed.as_span();
                     let mut raw_task = None;
                     let mut parameters: HashMap<String, String> = HashMap::new();
+                    let mut on = vec![];
 
                     for pair in parsed.into_inner() {
                         match pair.as_rule() {
@@ -51,6 +180,11 @@ impl Plan {
                                     }
                                 }
                             }
+                            Rule::on => {
+                                for pair in pair.into_inner() {
+                                    on.push(parse_str(&mut pair.into_inner())?);
+                                }
+                            }
                             Rule::kwarg => {
                                 let (key, val) = parse_kwarg(&mut pair.into_inner())?;
                                 parameters.insert(key, val);
@@ -59,17 +193,130 @@ impl Plan {
                         }
                     }
 
+                    let escalation =
+                        Escalation::from_parameters(&mut parameters).map_err(|message| {
+                            PestError::new_from_span(
+                                ErrorVariant::CustomError { message },
+                                span.clone(),
+                            )
+                        })?;
+
+                    let invalid = |message| {
+                        PestError::new_from_span(
+                            ErrorVariant::CustomError { message },
+                            span.clone(),
+                        )
+                    };
+                    let timeout = match parameters.remove("timeout") {
+                        Some(timeout) => Some(parse_timeout(&timeout).map_err(invalid)?),
+                        None => None,
+                    };
+                    let retries = match parameters.remove("retries") {
+                        Some(retries) => retries.parse().map_err(|_| {
+                            invalid(format!("retries must be a whole number, not `{}`", retries))
+                        })?,
+                        None => 0,
+                    };
+                    let retry_delay = match parameters.remove("retry_delay") {
+                        Some(delay) => Some(parse_duration(&delay).map_err(invalid)?),
+                        None => None,
+                    };
+                    let until = parameters.remove("until");
+                    let ignore_errors = match parameters.remove("ignore_errors").as_deref() {
+                        Some("true") => true,
+                        Some("false") | None => false,
+                        Some(other) => {
+                            return Err(invalid(format!(
+                                "ignore_errors must be either true or false, not `{}`",
+                                other
+                            )))
+                        }
+                    };
+
                     if let Some(task) = raw_task {
-                        plan.tasks.push(ExecutableTask::new(task, parameters));
+                        let mut task = ExecutableTask::new(task, parameters);
+                        task.escalation = escalation;
+                        task.timeout = timeout;
+                        task.retries = retries;
+                        task.retry_delay = retry_delay.unwrap_or(task.retry_delay);
+                        task.until = until;
+                        task.ignore_errors = ignore_errors;
+                        task.on = on;
+                        plan.tasks.push(task);
                     }
                 }
                 _ => {}
             }
         }
 
+        plan.share_variables();
         Ok(plan)
     }
 
+    /**
+     * Give values to the plan's parameters, which take precedence over those
+     * of the targets and the defaults
+     */
+    pub fn with_parameters(mut self, given: HashMap<String, String>) -> Result<Self, String> {
+        for name in given.keys() {
+            if !self.variables.parameters.iter().any(|p| p.name == *name) {
+                return Err(format!("The plan has no parameter named `{}`", name));
+            }
+        }
+        self.variables.given = given;
+        self.share_variables();
+        Ok(self)
+    }
+
+    /**
+     * Hand the variables to every task, so that they can be filled in once
+     * the target is known
+     */
+    fn share_variables(&mut self) {
+        let variables = Arc::new(self.variables.clone());
+        for task in self.tasks.iter_mut() {
+            task.variables = Some(variables.clone());
+        }
+    }
+
+    /**
+     * Make sure that every task of the plan has targets in the inventory to
+     * run on, before anything is run. The target or group given for the whole
+     * plan may be left out when every task says where it runs with `on`.
+     */
+    pub fn validate(&self, inventory: &Inventory, targets: Option<&str>) -> Result<(), String> {
+        if let Some(targets) = targets {
+            if !inventory.groups.iter().any(|g| g.name == targets)
+                && inventory.target(targets).is_none()
+            {
+                return Err(format!(
+                    "No target or group named `{}` in the inventory",
+                    targets
+                ));
+            }
+        }
+        for (step, task) in self.tasks.iter().enumerate() {
+            if task.on.is_empty() && targets.is_none() {
+                return Err(format!(
+                    "Task {} (`{}`) has no `on`, so the plan needs targets to run on",
+                    step + 1,
+                    task.task.name
+                ));
+            }
+            for pattern in task.on.iter() {
+                if inventory.select(pattern).is_empty() {
+                    return Err(format!(
+                        "Task {} (`{}`) runs on `{}`, which matches no targets in the inventory",
+                        step + 1,
+                        task.task.name,
+                        pattern
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn from_path(path: &PathBuf) -> Result<Self, PestError<Rule>> {
         use std::fs::File;
         use std::io::Read;
@@ -79,42 +326,41 @@ impl Plan {
                 let mut contents = String::new();
 
                 if let Err(e) = file.read_to_string(&mut contents) {
-                    return Err(PestError::new_from_pos(
+                    Err(PestError::new_from_pos(
                         ErrorVariant::CustomError {
                             message: format!("{}", e),
                         },
                         pest::Position::from_start(""),
-                    ));
+                    ))
                 } else {
-                    return Self::from_str(&contents);
+                    Self::from_str(&contents)
                 }
             }
-            Err(e) => {
-                return Err(PestError::new_from_pos(
-                    ErrorVariant::CustomError {
-                        message: format!("{}", e),
-                    },
-                    pest::Position::from_start(""),
-                ));
-            }
+            Err(e) => Err(PestError::new_from_pos(
+                ErrorVariant::CustomError {
+                    message: format!("{}", e),
+                },
+                pest::Position::from_start(""),
+            )),
         }
     }
 }
 
+#[allow(clippy::result_large_err)]
 fn parse_kwarg(parser: &mut Pairs<Rule>) -> Result<(String, String), PestError<Rule>> {
     let mut identifier = None;
     let mut arg = None;
 
-    while let Some(parsed) = parser.next() {
+    for parsed in parser {
         match parsed.as_rule() {
             Rule::identifier => identifier = Some(parsed.as_str().to_string()),
-            Rule::arg => arg = Some(parse_str(&mut parsed.into_inner())?),
+            Rule::arg => arg = Some(parse_arg(&mut parsed.into_inner())?),
             _ => {}
         }
     }
 
-    if identifier.is_some() && arg.is_some() {
-        return Ok((identifier.unwrap(), arg.unwrap()));
+    if let (Some(identifier), Some(arg)) = (identifier, arg) {
+        return Ok((identifier, arg));
     }
     Err(PestError::new_from_pos(
         ErrorVariant::CustomError {
@@ -125,12 +371,81 @@ fn parse_kwarg(parser: &mut Pairs<Rule>) -> Result<(String, String), PestError<R
     ))
 }
 
+#[allow(clippy::result_large_err)]
+fn parse_parameter(parser: &mut Pairs<Rule>) -> Result<Parameter, PestError<Rule>> {
+    let mut parameter = Parameter::default();
+    for parsed in parser {
+        match parsed.as_rule() {
+            Rule::identifier => parameter.name = parsed.as_str().to_string(),
+            Rule::required => parameter.required = parsed.into_inner().as_str() == "true",
+            Rule::default_value => {
+                parameter.default = Some(parse_str(&mut parsed.into_inner())?);
+            }
+            Rule::help => parameter.help = Some(parse_str(&mut parsed.into_inner())?),
+            _ => {}
+        }
+    }
+    Ok(parameter)
+}
+
+/**
+ * Timeouts are given in seconds
+ */
+fn parse_timeout(timeout: &str) -> Result<Duration, String> {
+    timeout.parse().map(Duration::from_secs).map_err(|_| {
+        format!(
+            "timeout must be a whole number of seconds, not `{}`",
+            timeout
+        )
+    })
+}
+
+/**
+ * Durations are a whole number followed by their unit: `ms`, `s`, `m` or `h`,
+ * seconds are assumed when there is none
+ */
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "`{}` is not a duration such as `500ms`, `10s`, `5m` or `1h`",
+            duration
+        )
+    };
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (count, unit) = duration.split_at(split);
+    let count: u64 = count.parse().map_err(|_| invalid())?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(count)),
+        "" | "s" => Ok(Duration::from_secs(count)),
+        "m" => Ok(Duration::from_secs(count.saturating_mul(60))),
+        "h" => Ok(Duration::from_secs(count.saturating_mul(60 * 60))),
+        _ => Err(invalid()),
+    }
+}
+
+/**
+ * Parser utility function to turn an argument into its string value, bools
+ * become 'true' or 'false' and numbers are left as they were written
+ */
+#[allow(clippy::result_large_err)]
+fn parse_arg(parser: &mut Pairs<Rule>) -> Result<String, PestError<Rule>> {
+    if let Some(parsed) = parser.peek() {
+        if parsed.as_rule() == Rule::bool || parsed.as_rule() == Rule::number {
+            return Ok(parsed.as_str().to_string());
+        }
+    }
+    parse_str(parser)
+}
+
 /**
  * Parser utility function to fish out the _actual_ string value for something
  * that is looking like a string Rule
  */
+#[allow(clippy::result_large_err)]
 fn parse_str(parser: &mut Pairs<Rule>) -> Result<String, PestError<Rule>> {
-    while let Some(parsed) = parser.next() {
+    for parsed in parser {
         match parsed.as_rule() {
             Rule::string => {
                 return parse_str(&mut parsed.into_inner());
@@ -150,13 +465,13 @@ fn parse_str(parser: &mut Pairs<Rule>) -> Result<String, PestError<Rule>> {
             _ => {}
         }
     }
-    return Err(PestError::new_from_pos(
+    Err(PestError::new_from_pos(
         ErrorVariant::CustomError {
             message: "Could not parse out a string value".to_string(),
         },
         /* TODO: Find a better thing to report */
         pest::Position::from_start(""),
-    ));
+    ))
 }
 
 #[cfg(test)]
@@ -196,4 +511,210 @@ task '../tasks/echo' {
         let plan = Plan::from_str(buf).expect("Failed to parse the plan");
         assert_eq!(plan.tasks.len(), 2);
     }
+
+    #[test]
+    fn parse_plan_with_escalation() {
+        let buf = r#"task '../tasks/echo' {
+                        msg = 'Hello'
+                        become = true
+                        become_user = 'www'
+                    }"#;
+        let plan = Plan::from_str(buf).expect("Failed to parse the plan");
+        let task = &plan.tasks[0];
+        assert!(task.escalation.is_enabled());
+        assert_eq!(task.escalation.user(), "www");
+        assert_eq!(task.parameters.len(), 1);
+    }
+
+    #[test]
+    fn parse_plan_with_timeout() {
+        let buf = r#"task '../tasks/echo' {
+                        msg = 'Hello'
+                        timeout = 90
+                    }"#;
+        let plan = Plan::from_str(buf).expect("Failed to parse the plan");
+        assert_eq!(plan.tasks[0].timeout(), Some(Duration::from_secs(90)));
+        assert_eq!(plan.tasks[0].parameters.len(), 1);
+
+        let buf = r#"task '../tasks/echo' {
+                        timeout = 'forever'
+                    }"#;
+        assert!(Plan::from_str(buf).is_err());
+    }
+
+    #[test]
+    fn parse_plan_with_retries() {
+        let buf = r#"task '../tasks/echo' {
+                        msg = 'Hello'
+                        retries = 3
+                        retry_delay = '2m'
+                        until = 'test -f /tmp/hello'
+                        ignore_errors = true
+                    }"#;
+        let plan = Plan::from_str(buf).expect("Failed to parse the plan");
+        let task = &plan.tasks[0];
+        assert_eq!(task.retries, 3);
+        assert_eq!(task.retry_delay, Duration::from_secs(120));
+        assert_eq!(task.until.as_deref(), Some("test -f /tmp/hello"));
+        assert!(task.ignore_errors);
+        assert_eq!(task.parameters.len(), 1);
+
+        let buf = r#"task '../tasks/echo' {
+                        retry_delay = 'soon'
+                    }"#;
+        assert!(Plan::from_str(buf).is_err());
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("10 days").is_err());
+    }
+
+    #[test]
+    fn parse_plan_with_on() {
+        let buf = r#"task '../tasks/echo' {
+                        on = 'db'
+                        msg = 'Hello'
+                    }
+                    task '../tasks/echo' {
+                        on = ['web-*', 'lb',]
+                        msg = 'Hello'
+                    }"#;
+        let plan = Plan::from_str(buf).expect("Failed to parse the plan");
+        assert_eq!(plan.tasks[0].on, vec!["db"]);
+        assert_eq!(plan.tasks[1].on, vec!["web-*", "lb"]);
+        assert_eq!(plan.tasks[1].parameters.len(), 1);
+    }
+
+    #[test]
+    fn validate_against_inventory() {
+        let inventory: Inventory = r#"
+groups:
+  - name: db
+    targets: [db-1]
+targets:
+  - name: db-1
+    uri: 192.168.1.1
+  - name: web-1
+    uri: 192.168.1.2
+config:
+  transport: ssh
+"#
+        .parse()
+        .expect("Failed to parse the inventory");
+
+        let plan = Plan::from_str(
+            r#"task '../tasks/echo' {
+                   on = ['db', 'web-*']
+                   msg = 'Hello'
+               }"#,
+        )
+        .expect("Failed to parse the plan");
+        assert!(plan.validate(&inventory, None).is_ok());
+        assert!(plan.validate(&inventory, Some("db")).is_ok());
+        assert!(plan.validate(&inventory, Some("lb")).is_err());
+
+        let plan = Plan::from_str(
+            r#"task '../tasks/echo' {
+                   on = 'lb'
+                   msg = 'Hello'
+               }"#,
+        )
+        .expect("Failed to parse the plan");
+        assert!(plan.validate(&inventory, Some("db")).is_err());
+
+        let plan = Plan::from_str(r#"task '../tasks/echo' { msg = 'Hello' }"#)
+            .expect("Failed to parse the plan");
+        assert!(plan.validate(&inventory, None).is_err());
+        assert!(plan.validate(&inventory, Some("web-1")).is_ok());
+    }
+
+    #[test]
+    fn parse_plan_with_variables() {
+        let buf = r#"parameters {
+                        version {
+                            required = true
+                            help = 'Version of nginx to install'
+                            type = string
+                        }
+                        flavor {
+                            default = 'light'
+                            help = 'Which flavor of the package'
+                            type = string
+                        }
+                    }
+                    let package = 'nginx-{{flavor}}-{{version}}'
+
+                    task '../tasks/echo' {
+                        msg = 'Installing {{package}}'
+                    }"#;
+        let plan = Plan::from_str(buf).expect("Failed to parse the plan");
+        let variables = &plan.variables;
+        assert_eq!(variables.parameters.len(), 2);
+        assert!(variables.parameters[0].required);
+        assert_eq!(variables.parameters[1].default.as_deref(), Some("light"));
+        assert_eq!(variables.lets.len(), 1);
+
+        let target: Target = serde_yaml::from_str(
+            r#"
+name: alpha
+uri: 192.168.1.1
+config:
+  vars:
+    version: 1.18
+    flavor: full"#,
+        )
+        .expect("Failed to parse the target");
+        let task = plan.tasks[0]
+            .for_target(&target)
+            .expect("Failed to fill in");
+        assert_eq!(task.parameters["msg"], "Installing nginx-full-1.18");
+
+        // The command line takes precedence over the target
+        let mut given = HashMap::new();
+        given.insert("version".to_string(), "1.20".to_string());
+        let plan = plan
+            .with_parameters(given)
+            .expect("Failed to give parameters");
+        let task = plan.tasks[0]
+            .for_target(&target)
+            .expect("Failed to fill in");
+        assert_eq!(task.parameters["msg"], "Installing nginx-full-1.20");
+
+        let mut given = HashMap::new();
+        given.insert("verison".to_string(), "1.20".to_string());
+        assert!(plan.with_parameters(given).is_err());
+    }
+
+    #[test]
+    fn resolve_missing_variables() {
+        let mut variables = Variables::default();
+        variables.parameters.push(Parameter {
+            name: "version".into(),
+            required: true,
+            ..Default::default()
+        });
+        let target: Target = serde_yaml::from_str("name: alpha\nuri: 192.168.1.1")
+            .expect("Failed to parse the target");
+        assert!(variables.resolve(&target).is_err());
+
+        variables.given.insert("version".into(), "1.2".into());
+        let values = variables.resolve(&target).expect("Failed to resolve");
+        assert_eq!(render("v{{version}}", &values), Ok("v1.2".into()));
+        assert!(render("{{flavor}}", &values).is_err());
+    }
+
+    #[test]
+    fn redefine_variable() {
+        let buf = r#"let package = 'nginx'
+                    let package = 'apache'
+                    task '../tasks/echo' {
+                        msg = '{{package}}'
+                    }"#;
+        assert!(Plan::from_str(buf).is_err());
+    }
 }
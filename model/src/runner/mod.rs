@@ -0,0 +1,1414 @@
+use crate::escalation::shell_quote;
+use crate::events::{self, Event};
+use crate::inventory::{Group, Inventory, Target};
+use crate::transport::{self, Stream, Transport};
+use crate::{Escalation, ExecutableTask, TransportError};
+
+use chrono::{DateTime, Local};
+use log::*;
+use serde::{Serialize, Serializer};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The names of the files placed in a run's working directory
+const REMOTE_SCRIPT: &str = "._zap_command";
+const REMOTE_UNLESS: &str = "._zap_unless";
+const REMOTE_UNTIL: &str = "._zap_until";
+const REMOTE_ARGS: &str = "._zap_args.json";
+const REMOTE_BUNDLE: &str = "._zap_files.tar";
+/// Where working directories are created unless the target's `tmpdir` is set
+const DEFAULT_TMPDIR: &str = "${TMPDIR:-/tmp}";
+/// Scripts with a timeout announce their pid on stderr, following this
+const PID_MARKER: &str = "zap-pid ";
+/// The exit code given to tasks which ran out of time, like timeout(1) does
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+/// How often the connections which aren't in use are sent keepalives, well
+/// within the interval the ssh transport asks them for
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/**
+ * What came of running a task on a target
+ */
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ExecutionResult {
+    /// The name of the target the task ran on
+    pub target: String,
+    /// The name of the task
+    pub task: String,
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+    /// When the runner started on the target, including connecting to it
+    pub started: DateTime<Local>,
+    /// How long it took, across every attempt
+    #[serde(serialize_with = "as_seconds")]
+    pub duration: Duration,
+    /// The task ran and succeeded, skipped tasks and dry-runs change nothing
+    pub changed: bool,
+    /// The task was skipped because of its `provides` or `unless`
+    pub skipped: bool,
+    /// The task was killed for running longer than its timeout
+    pub timed_out: bool,
+    /// How many times the task was run on the target, including retries
+    pub attempts: u32,
+}
+
+impl ExecutionResult {
+    pub fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+}
+
+pub(crate) fn as_seconds<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/**
+ * The outcome of running on each target of a group, keyed by the target's
+ * name: either the result of the task or the error which kept it from
+ * running to completion
+ */
+pub type GroupResults = BTreeMap<String, Result<ExecutionResult, TransportError>>;
+
+/**
+ * Creates a new, unconnected, transport for the given target
+ */
+pub type TransportFactory = dyn Fn(&Target) -> Box<dyn Transport> + Send + Sync;
+
+/**
+ * Connected transports which aren't in use, keyed by target name
+ */
+type Connections = Arc<Mutex<HashMap<String, Box<dyn Transport>>>>;
+
+/**
+ * Receives the events from the runner as they happen, from whichever thread
+ * the target is being run on
+ */
+pub type Reporter = dyn Fn(&Event) + Send + Sync;
+
+/**
+ * The Runner takes care of running tasks on targets, regardless of which
+ * transport is used to reach them.
+ *
+ * It resolves the targets from the inventory, checks the `provides` and
+ * `unless` guards, renders the script, handles dry-runs and collects the
+ * results, leaving only the primitive operations to the transports.
+ *
+ * What happens along the way is reported as events, which are printed for a
+ * person to read unless another reporter is given.
+ *
+ * Connections to the targets are kept open for as long as the runner is
+ * around, so that every task of a plan can reuse them, and are sent keepalives
+ * from a thread of their own while they wait. They are closed when the runner
+ * is dropped, or by calling `disconnect`.
+ */
+pub struct Runner<'a> {
+    pub inventory: &'a Inventory,
+    /// Show the script which would be run rather than running it
+    pub dry_run: bool,
+    /// How many targets of a group to run on at once
+    pub parallel: usize,
+    transports: Box<TransportFactory>,
+    reporter: Box<Reporter>,
+    connections: Connections,
+    /// Stops the keepalives once the runner is dropped
+    _keepalives: mpsc::Sender<()>,
+}
+
+impl<'a> Runner<'a> {
+    pub fn new(inventory: &'a Inventory) -> Self {
+        let connections = Connections::default();
+        let (keepalives, stopped) = mpsc::channel();
+        let cached = connections.clone();
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(KEEPALIVE_INTERVAL) {
+                send_keepalives(&cached);
+            }
+            // Whatever was being kept alive as the runner disconnected
+            for (_, mut transport) in cached.lock().unwrap().drain() {
+                transport.disconnect();
+            }
+        });
+
+        Self {
+            inventory,
+            dry_run: false,
+            parallel: 1,
+            transports: Box::new(transport::for_target),
+            reporter: Box::new(events::print_human),
+            connections,
+            _keepalives: keepalives,
+        }
+    }
+
+    /**
+     * Use the given function to create the transport for each target, rather
+     * than the one the target is configured with
+     */
+    pub fn with_transports<F>(mut self, transports: F) -> Self
+    where
+        F: Fn(&Target) -> Box<dyn Transport> + Send + Sync + 'static,
+    {
+        self.transports = Box::new(transports);
+        self
+    }
+
+    /**
+     * Send the events to the given function, rather than printing them
+     */
+    pub fn with_reporter<F>(mut self, reporter: F) -> Self
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        self.reporter = Box::new(reporter);
+        self
+    }
+
+    /**
+     * Report the event, allowing callers to mix in events of their own
+     */
+    pub fn report(&self, event: Event) {
+        (self.reporter)(&event)
+    }
+
+    /**
+     * Run the task on a single target
+     */
+    pub fn run(
+        &self,
+        task: &ExecutableTask,
+        target: &Target,
+    ) -> Result<ExecutionResult, TransportError> {
+        self.report(Event::TaskStart {
+            task: task.task.name.clone(),
+            targets: target.name.clone(),
+        });
+        self.run_on(task, target)
+    }
+
+    /**
+     * Run the task on every target in the group, up to `parallel` of them at
+     * once. Each target gets a transport of its own.
+     *
+     * Every target's outcome is returned, failures on one target never stop
+     * the others from running.
+     */
+    pub fn run_group(&self, task: &ExecutableTask, group: &Group) -> GroupResults {
+        let targets: Vec<Target> = group
+            .targets
+            .iter()
+            .filter_map(|name| {
+                let target = self.inventory.target_in_group(name, group);
+                if target.is_none() {
+                    warn!("No target named `{}` in the inventory", name);
+                }
+                target
+            })
+            .collect();
+        self.run_targets(task, &group.name, &targets)
+    }
+
+    /**
+     * Run the task on each of the targets, which are referred to by `name`,
+     * in the same way as the targets of a group
+     */
+    pub fn run_targets(
+        &self,
+        task: &ExecutableTask,
+        name: &str,
+        targets: &[Target],
+    ) -> GroupResults {
+        self.report(Event::TaskStart {
+            task: task.task.name.clone(),
+            targets: name.to_string(),
+        });
+        let next = AtomicUsize::new(0);
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            for _ in 0..self.parallel.max(1).min(targets.len()) {
+                let sender = sender.clone();
+                let (next, targets) = (&next, &targets);
+                scope.spawn(move || loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let target = match targets.get(index) {
+                        Some(target) => target,
+                        None => break,
+                    };
+                    let outcome = self.run_on(task, target);
+                    // The receiver outlives every worker
+                    let _ = sender.send((index, outcome));
+                });
+            }
+        });
+        drop(sender);
+
+        receiver
+            .iter()
+            .map(|(index, outcome)| (targets[index].name.clone(), outcome))
+            .collect()
+    }
+
+    /**
+     * Run the task on the target, reporting how it went
+     */
+    fn run_on(
+        &self,
+        task: &ExecutableTask,
+        target: &Target,
+    ) -> Result<ExecutionResult, TransportError> {
+        self.report(Event::TargetStart {
+            task: task.task.name.clone(),
+            target: target.name.clone(),
+            uri: target.uri.clone(),
+        });
+        let outcome = task
+            .for_target(target)
+            .map_err(TransportError::GeneralError)
+            .and_then(|task| self.run_attempts(&task, target));
+        match &outcome {
+            Ok(result) => self.report(Event::Result(result.clone())),
+            Err(err) => self.report(Event::Error {
+                task: Some(task.task.name.clone()),
+                target: Some(target.name.clone()),
+                error: err.to_string(),
+            }),
+        }
+        outcome
+    }
+
+    /**
+     * Run the task on the target until it succeeds, or it has run out of
+     * retries
+     */
+    fn run_attempts(
+        &self,
+        task: &ExecutableTask,
+        target: &Target,
+    ) -> Result<ExecutionResult, TransportError> {
+        let started = Local::now();
+        let timer = Instant::now();
+        let attempts = task.retries.saturating_add(1);
+        let mut attempt = 1;
+        loop {
+            let outcome = self.connect_and_execute(task, target);
+            let reason = match &outcome {
+                Ok(result) if result.success() => None,
+                Ok(result) if result.timed_out => Some("timed out".to_string()),
+                Ok(result) => Some(format!("exited {}", result.exit_code)),
+                Err(err) => Some(err.to_string()),
+            };
+            match reason {
+                Some(reason) if attempt < attempts => {
+                    let delay = task.retry_delay(attempt);
+                    self.report(Event::Retry {
+                        task: task.task.name.clone(),
+                        target: target.name.clone(),
+                        attempt,
+                        attempts,
+                        reason,
+                        delay,
+                    });
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                _ => {
+                    break outcome.map(|result| ExecutionResult {
+                        started,
+                        duration: timer.elapsed(),
+                        attempts: attempt,
+                        ..result
+                    })
+                }
+            }
+        }
+    }
+
+    fn connect_and_execute(
+        &self,
+        task: &ExecutableTask,
+        target: &Target,
+    ) -> Result<ExecutionResult, TransportError> {
+        let escalation = task.escalation_for(target);
+        let (mut transport, reused) = self.checkout(target)?;
+        let mut workdir = make_workdir(transport.as_mut(), target, &escalation);
+        if workdir.is_err() && reused {
+            // Nothing has happened on the target yet, so it's safe to start
+            // over on a fresh connection
+            debug!("Lost the connection to `{}`, reconnecting", target.name);
+            transport.disconnect();
+            transport = self.connect(target)?;
+            workdir = make_workdir(transport.as_mut(), target, &escalation);
+        }
+        let result = workdir.and_then(|workdir| {
+            let result = self.execute(transport.as_mut(), task, target, &workdir);
+            remove_workdir(transport.as_mut(), &workdir, &escalation);
+            result
+        });
+        self.checkin(target, transport, &result);
+        result
+    }
+
+    fn connect(&self, target: &Target) -> Result<Box<dyn Transport>, TransportError> {
+        let mut transport = (self.transports)(target);
+        transport.connect(target)?;
+        Ok(transport)
+    }
+
+    /**
+     * Take the connection to the target out of the cache, or else connect to
+     * it, returning whether the connection was reused
+     *
+     * A cached connection is sent a keepalive first, once the cache has been
+     * let go of, and is replaced if it turns out to have been dropped.
+     */
+    fn checkout(&self, target: &Target) -> Result<(Box<dyn Transport>, bool), TransportError> {
+        let cached = self.connections.lock().unwrap().remove(&target.name);
+        if let Some(mut transport) = cached {
+            match transport.keepalive() {
+                Ok(_) => {
+                    debug!("Reusing the connection to `{}`", target.name);
+                    return Ok((transport, true));
+                }
+                Err(err) => {
+                    debug!("Dropping the connection to `{}`: {}", target.name, err);
+                    transport.disconnect();
+                }
+            }
+        }
+        self.connect(target).map(|transport| (transport, false))
+    }
+
+    /**
+     * Keep the connection around for the next task, unless it has failed
+     */
+    fn checkin<T>(
+        &self,
+        target: &Target,
+        mut transport: Box<dyn Transport>,
+        result: &Result<T, TransportError>,
+    ) {
+        if let Err(TransportError::ConnectionFailed(_)) | Err(TransportError::NotConnected) = result
+        {
+            transport.disconnect();
+            return;
+        }
+        let replaced = self
+            .connections
+            .lock()
+            .unwrap()
+            .insert(target.name.clone(), transport);
+        // The same target was run on twice at once
+        if let Some(mut replaced) = replaced {
+            replaced.disconnect();
+        }
+    }
+
+    /**
+     * Close every connection which has been kept open
+     */
+    pub fn disconnect(&self) {
+        let connections: Vec<_> = self.connections.lock().unwrap().drain().collect();
+        for (name, mut transport) in connections {
+            debug!("Disconnecting from `{}`", name);
+            transport.disconnect();
+        }
+    }
+
+    /**
+     * Run the task with its files placed in the working directory
+     */
+    fn execute(
+        &self,
+        transport: &mut dyn Transport,
+        task: &ExecutableTask,
+        target: &Target,
+        workdir: &Path,
+    ) -> Result<ExecutionResult, TransportError> {
+        let escalation = task.escalation_for(target);
+        let result = ExecutionResult {
+            target: target.name.clone(),
+            task: task.task.name.clone(),
+            ..Default::default()
+        };
+        let skipped = ExecutionResult {
+            skipped: true,
+            ..result.clone()
+        };
+        let deadline = task.timeout().map(|timeout| Instant::now() + timeout);
+
+        if let Some(provides) = task.parameters.get("provides") {
+            debug!(
+                "A `provides` parameter was given, checking to see if {} exists on the remote",
+                provides
+            );
+            if transport.stat(Path::new(provides))?.is_some() {
+                debug!("File {} exists, skipping task", provides);
+                return Ok(skipped);
+            }
+        }
+
+        if let Some(unless) = task.parameters.get("unless") {
+            debug!("An `unless` parameter was given, running {}", unless);
+            // The check's output is shown, but isn't part of the task's result
+            let mut check = Capture::new(self, &task.task.name, &target.name);
+            let status = run_script(
+                transport,
+                &workdir.join(REMOTE_UNLESS),
+                unless.as_bytes(),
+                None,
+                &escalation,
+                deadline,
+                &mut |stream, text| check.write(stream, text),
+            );
+            check.flush();
+            match status? {
+                Some(0) => {
+                    debug!("`unless` script returned 0, skipping the task");
+                    return Ok(skipped);
+                }
+                None => {
+                    return Ok(ExecutionResult {
+                        exit_code: TIMEOUT_EXIT_CODE,
+                        timed_out: true,
+                        ..result
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+
+        let script = match task.task.script.as_bytes(Some(&task.parameters)) {
+            Some(script) => script,
+            None => {
+                return Err(TransportError::GeneralError(
+                    "No script available to run for task!".into(),
+                ));
+            }
+        };
+
+        if self.dry_run {
+            self.report(Event::DryRun {
+                task: result.task.clone(),
+                target: result.target.clone(),
+                script: String::from_utf8_lossy(&script).into_owned(),
+            });
+            return Ok(result);
+        }
+
+        if let Some(bundle) = task.task.bundle().map_err(|e| {
+            TransportError::TransferFailed(format!("failed to bundle the task's files: {}", e))
+        })? {
+            upload_bundle(transport, workdir, &bundle)?;
+        }
+
+        let mut args_path = None;
+        if task.task.script.has_file() {
+            let args = serde_json::to_string(&task.parameters).map_err(|e| {
+                TransportError::GeneralError(format!(
+                    "failed to serialize parameters for task: {}",
+                    e
+                ))
+            })?;
+            // The become user must be able to read the arguments too
+            let mode = if escalation.is_enabled() {
+                0o444
+            } else {
+                0o400
+            };
+            let path = workdir.join(REMOTE_ARGS);
+            transport.put(&path, args.as_bytes(), mode)?;
+            args_path = Some(path);
+        }
+
+        let mut capture = Capture::new(self, &task.task.name, &target.name);
+        let status = run_script(
+            transport,
+            &workdir.join(REMOTE_SCRIPT),
+            &script,
+            args_path.as_deref(),
+            &escalation,
+            deadline,
+            &mut |stream, text| capture.write(stream, text),
+        );
+        capture.flush();
+
+        let (mut exit_code, mut timed_out) = match status? {
+            Some(exit_code) => (exit_code, false),
+            None => (TIMEOUT_EXIT_CODE, true),
+        };
+
+        if let (0, Some(until)) = (exit_code, &task.until) {
+            debug!("An `until` check was given, running {}", until);
+            // Like `unless`, the check's output is shown but isn't part of the
+            // task's result
+            let mut check = Capture::new(self, &task.task.name, &target.name);
+            let status = run_script(
+                transport,
+                &workdir.join(REMOTE_UNTIL),
+                until.as_bytes(),
+                None,
+                &escalation,
+                deadline,
+                &mut |stream, text| check.write(stream, text),
+            );
+            check.flush();
+            match status? {
+                Some(0) => {}
+                Some(status) => {
+                    debug!("`until` check returned {}, the task failed", status);
+                    exit_code = status;
+                }
+                None => {
+                    exit_code = TIMEOUT_EXIT_CODE;
+                    timed_out = true;
+                }
+            }
+        }
+
+        Ok(ExecutionResult {
+            exit_code,
+            stdout: capture.stdout,
+            stderr: capture.stderr,
+            changed: exit_code == 0,
+            timed_out,
+            ..result
+        })
+    }
+}
+
+impl Drop for Runner<'_> {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+/**
+ * Send a keepalive over each connection which is waiting in the cache, so the
+ * target doesn't drop it while other tasks are being run, and let go of those
+ * which have been dropped already.
+ *
+ * Each connection is taken out of the cache while its keepalive is sent, so
+ * that the cache isn't held up by a target which is slow to answer.
+ */
+fn send_keepalives(connections: &Mutex<HashMap<String, Box<dyn Transport>>>) {
+    let names: Vec<String> = connections.lock().unwrap().keys().cloned().collect();
+    for name in names {
+        let mut transport = match connections.lock().unwrap().remove(&name) {
+            Some(transport) => transport,
+            // Checked out in the meantime
+            None => continue,
+        };
+        if let Err(err) = transport.keepalive() {
+            debug!("Dropping the connection to `{}`: {}", name, err);
+            transport.disconnect();
+            continue;
+        }
+        match connections.lock().unwrap().entry(name) {
+            // A fresh connection to the target was checked in meanwhile
+            Entry::Occupied(_) => transport.disconnect(),
+            Entry::Vacant(entry) => {
+                entry.insert(transport);
+            }
+        }
+    }
+}
+
+/**
+ * Run the command on the target as the login user, returning what it wrote
+ * to stdout, or what it wrote to stderr as the error if it failed
+ */
+fn capture(transport: &mut dyn Transport, command: &str) -> Result<String, TransportError> {
+    let (mut stdout, mut stderr) = (String::new(), String::new());
+    let status = transport.exec(
+        command,
+        &Escalation::default(),
+        &mut |stream, text| match stream {
+            Stream::Stdout => stdout.push_str(text),
+            Stream::Stderr => stderr.push_str(text),
+        },
+    )?;
+    if status != 0 {
+        return Err(TransportError::ExecutionFailed(format!(
+            "`{}` exited {}: {}",
+            command,
+            status,
+            stderr.trim()
+        )));
+    }
+    Ok(stdout.trim().to_string())
+}
+
+/**
+ * Unpack the task's bundle of support files into the working directory
+ */
+fn upload_bundle(
+    transport: &mut dyn Transport,
+    workdir: &Path,
+    bundle: &[u8],
+) -> Result<(), TransportError> {
+    let path = workdir.join(REMOTE_BUNDLE);
+    transport.put(&path, bundle, 0o600)?;
+    let unpack = format!(
+        "cd {} && tar -xf {} && rm -f {}",
+        shell_quote(&workdir.to_string_lossy()),
+        REMOTE_BUNDLE,
+        REMOTE_BUNDLE
+    );
+    capture(transport, &unpack).map(|_| ())
+}
+
+/**
+ * Create a private working directory for a run on the target, inside its
+ * `tmpdir`, so that concurrent runs can't interfere with each other and
+ * nothing is left at a predictable path
+ */
+fn make_workdir(
+    transport: &mut dyn Transport,
+    target: &Target,
+    escalation: &Escalation,
+) -> Result<PathBuf, TransportError> {
+    let template = match target.config.as_ref().and_then(|c| c.tmpdir.as_ref()) {
+        Some(tmpdir) => shell_quote(&format!("{}/zap.XXXXXX", tmpdir)),
+        None => format!("\"{}/zap.XXXXXX\"", DEFAULT_TMPDIR),
+    };
+    let created = capture(transport, &format!("mktemp -d {}", template))?;
+    if created.is_empty() {
+        return Err(TransportError::ExecutionFailed(
+            "mktemp did not name the working directory it created".into(),
+        ));
+    }
+    debug!("Running in {} on `{}`", created, target.name);
+
+    if escalation.is_enabled() {
+        // The become user needs to get to the files, without being able to
+        // list the directory
+        if let Err(err) = transport.chmod(Path::new(&created), 0o711) {
+            remove_workdir(transport, Path::new(&created), escalation);
+            return Err(err);
+        }
+    }
+    Ok(PathBuf::from(created))
+}
+
+/**
+ * Remove the working directory and everything in it, even what the become
+ * user left behind
+ */
+fn remove_workdir(transport: &mut dyn Transport, workdir: &Path, escalation: &Escalation) {
+    let command = format!("rm -rf {}", shell_quote(&workdir.to_string_lossy()));
+    let removed = capture(transport, &command).or_else(|err| {
+        if !escalation.is_enabled() {
+            return Err(err);
+        }
+        transport
+            .exec(&command, escalation, &mut |_, _| {})
+            .and_then(|status| match status {
+                0 => Ok(String::new()),
+                _ => Err(err),
+            })
+    });
+    if let Err(err) = removed {
+        warn!("Failed to remove {}: {}", workdir.display(), err);
+    }
+}
+
+/**
+ * Copy the script over to `path` and execute it from the working directory it
+ * is in, passing it the arguments file if there is one
+ *
+ * Given a deadline, the script is killed if it is still running when the
+ * deadline passes, and None is returned.
+ */
+fn run_script(
+    transport: &mut dyn Transport,
+    path: &Path,
+    script: &[u8],
+    args: Option<&Path>,
+    escalation: &Escalation,
+    deadline: Option<Instant>,
+    output: &mut dyn FnMut(Stream, &str),
+) -> Result<Option<i32>, TransportError> {
+    // The become user must be able to run the script too
+    let mode = if escalation.is_enabled() {
+        0o755
+    } else {
+        0o700
+    };
+    transport.put(path, script, mode)?;
+
+    let relative = |path: &Path| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        shell_quote(&format!("./{}", name))
+    };
+    let workdir = path.parent().unwrap_or_else(|| Path::new("."));
+    let cd = format!("cd {}", shell_quote(&workdir.to_string_lossy()));
+    let mut run = relative(path);
+    if let Some(args) = args {
+        run = format!("{} {}", run, relative(args));
+    }
+    // A shell of its own keeps the `cd` inside whatever the command is
+    // wrapped in to escalate
+    let sh = |command: String| format!("/bin/sh -c {}", shell_quote(&command));
+
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => {
+            return transport
+                .exec(&sh(format!("{} && {}", cd, run)), escalation, output)
+                .map(Some)
+        }
+    };
+
+    // The shell becomes the script, so the pid it announces is the script's
+    let command = format!("{} && echo {}$$ >&2 && exec {}", cd, PID_MARKER, run);
+    let mut marker = PidMarker::default();
+    // Behind a pty, stderr arrives merged into stdout
+    let announced = if escalation.prompts_on_tty() {
+        Stream::Stdout
+    } else {
+        Stream::Stderr
+    };
+    let status = transport.exec_until(&sh(command), escalation, deadline, &mut |stream, text| {
+        if stream == announced {
+            output(stream, &marker.filter(text))
+        } else {
+            output(stream, text)
+        }
+    });
+    output(announced, &marker.finish());
+
+    let status = status?;
+    if status.is_none() {
+        kill_script(transport, marker.pid, escalation);
+    }
+    Ok(status)
+}
+
+/**
+ * Put an end to a script which ran out of time, along with everything in its
+ * process group if it leads one, or otherwise everything it started
+ */
+fn kill_script(transport: &mut dyn Transport, pid: Option<u32>, escalation: &Escalation) {
+    let pid = match pid {
+        Some(pid) => pid,
+        None => {
+            warn!("The script ran out of time before it could be killed");
+            return;
+        }
+    };
+    debug!("Killing the script, which ran out of time, at pid {}", pid);
+    let command = format!(
+        "tree() {{ echo $1; for child in $(pgrep -P $1); do tree $child; done; }}; \
+         kill -TERM -- -{pid} 2>/dev/null || kill -TERM $(tree {pid})",
+        pid = pid
+    );
+    match transport.exec(
+        &format!("/bin/sh -c {}", shell_quote(&command)),
+        escalation,
+        &mut |_, _| {},
+    ) {
+        Ok(0) => {}
+        Ok(status) => warn!(
+            "Failed to kill the script at pid {}, `kill` exited {}",
+            pid, status
+        ),
+        Err(err) => warn!("Failed to kill the script at pid {}: {}", pid, err),
+    }
+}
+
+/**
+ * PidMarker picks the pid which a script announces out of its stderr, or its
+ * stdout behind a pty, passing everything else along
+ */
+#[derive(Default)]
+struct PidMarker {
+    pid: Option<u32>,
+    found: bool,
+    /// Output which hasn't yet made up a whole line
+    pending: String,
+}
+
+impl PidMarker {
+    fn filter(&mut self, text: &str) -> String {
+        if self.found {
+            return text.to_string();
+        }
+        self.pending.push_str(text);
+
+        let mut passed = String::new();
+        while let Some(end) = self.pending.find('\n') {
+            let line: String = self.pending.drain(..=end).collect();
+            match line.trim_end().strip_prefix(PID_MARKER) {
+                Some(pid) => {
+                    self.pid = pid.parse().ok();
+                    self.found = true;
+                    passed.push_str(&self.pending);
+                    self.pending.clear();
+                    break;
+                }
+                // Anything the escalation wrote before the script started
+                None => passed.push_str(&line),
+            }
+        }
+        passed
+    }
+
+    fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/**
+ * Capture collects the output of a task on a target, and reports each line as
+ * soon as it is complete
+ */
+struct Capture<'a> {
+    runner: &'a Runner<'a>,
+    task: &'a str,
+    target: &'a str,
+    stdout: String,
+    stderr: String,
+    /// How much of stdout and stderr has been reported so far
+    reported: (usize, usize),
+}
+
+impl<'a> Capture<'a> {
+    fn new(runner: &'a Runner<'a>, task: &'a str, target: &'a str) -> Self {
+        Self {
+            runner,
+            task,
+            target,
+            stdout: String::new(),
+            stderr: String::new(),
+            reported: (0, 0),
+        }
+    }
+
+    fn write(&mut self, stream: Stream, text: &str) {
+        let (captured, reported) = match stream {
+            Stream::Stdout => (&mut self.stdout, &mut self.reported.0),
+            Stream::Stderr => (&mut self.stderr, &mut self.reported.1),
+        };
+        captured.push_str(text);
+        if let Some(end) = captured.rfind('\n') {
+            if end >= *reported {
+                let lines = captured[*reported..end].to_string();
+                *reported = end + 1;
+                self.report(stream, &lines);
+            }
+        }
+    }
+
+    /**
+     * Report whatever is left over which didn't end with a newline
+     */
+    fn flush(&mut self) {
+        if self.reported.0 < self.stdout.len() {
+            let rest = self.stdout[self.reported.0..].to_string();
+            self.reported.0 = self.stdout.len();
+            self.report(Stream::Stdout, &rest);
+        }
+        if self.reported.1 < self.stderr.len() {
+            let rest = self.stderr[self.reported.1..].to_string();
+            self.reported.1 = self.stderr.len();
+            self.report(Stream::Stderr, &rest);
+        }
+    }
+
+    fn report(&self, stream: Stream, lines: &str) {
+        let time = Local::now();
+        for line in lines.split('\n') {
+            self.runner.report(Event::Output {
+                task: self.task.to_string(),
+                target: self.target.to_string(),
+                stream,
+                text: line.to_string(),
+                time,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::escalation::BecomeMethod;
+    use crate::transport::mock::{MockTransport, Operation, Response, MOCK_TMPDIR};
+    use crate::Task;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    fn inventory() -> Inventory {
+        let buf = r#"
+groups:
+  - name: web
+    targets: [alpha, beta]
+targets:
+  - name: alpha
+    uri: 192.168.1.1
+  - name: beta
+    uri: 192.168.1.2
+config:
+  transport: ssh
+"#;
+        buf.parse().expect("Failed to parse the inventory")
+    }
+
+    fn echo(parameters: &[(&str, &str)]) -> ExecutableTask {
+        let task = Task::from_str("task Echo { script { inline = 'echo {{msg}}' } }")
+            .expect("Failed to parse the task");
+        let parameters: HashMap<String, String> = parameters
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        ExecutableTask::new(task, parameters)
+    }
+
+    fn run(runner: &Runner, task: &ExecutableTask, target: &Target) -> ExecutionResult {
+        runner.run(task, target).expect("Failed to run")
+    }
+
+    /// How the command is run in the mock's working directory
+    fn in_workdir(command: &str) -> String {
+        format!(
+            "/bin/sh -c {}",
+            shell_quote(&format!("cd {} && {}", shell_quote(MOCK_TMPDIR), command))
+        )
+    }
+
+    fn runner<'a>(inventory: &'a Inventory, mock: &MockTransport) -> Runner<'a> {
+        let mock = mock.clone();
+        Runner::new(inventory).with_transports(move |_| Box::new(mock.clone()))
+    }
+
+    #[test]
+    fn run_task() {
+        let inventory = inventory();
+        let mock = MockTransport::new();
+        mock.respond(
+            REMOTE_SCRIPT,
+            Response {
+                status: 2,
+                stdout: "hi\n".into(),
+                stderr: "partial".into(),
+                ..Default::default()
+            },
+        );
+        let target = inventory.target("alpha").unwrap();
+
+        let result = run(&runner(&inventory, &mock), &echo(&[("msg", "hi")]), &target);
+        assert_eq!(result.target, "alpha");
+        assert_eq!(result.task, "Echo");
+        assert_eq!(result.exit_code, 2);
+        assert_eq!(result.stdout, "hi\n");
+        assert_eq!(result.stderr, "partial");
+        assert!(!result.changed);
+        assert!(!result.skipped);
+        assert_eq!(
+            mock.operations(),
+            vec![
+                Operation::Connect("alpha".into()),
+                Operation::Exec(r#"mktemp -d "${TMPDIR:-/tmp}/zap.XXXXXX""#.into()),
+                Operation::Put {
+                    path: Path::new(MOCK_TMPDIR).join(REMOTE_SCRIPT),
+                    bytes: b"echo hi".to_vec(),
+                    mode: 0o700,
+                },
+                Operation::Exec(in_workdir("'./._zap_command'")),
+                Operation::Exec("rm -rf '/tmp/zap.mock'".into()),
+                Operation::Disconnect,
+            ]
+        );
+    }
+
+    #[test]
+    fn capture_lines() {
+        let inventory = inventory();
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let runner = Runner::new(&inventory).with_reporter(move |event| {
+            if let Event::Output { stream, text, .. } = event {
+                sender
+                    .lock()
+                    .unwrap()
+                    .send((*stream, text.clone()))
+                    .unwrap();
+            }
+        });
+
+        let mut capture = Capture::new(&runner, "Echo", "alpha");
+        capture.write(Stream::Stdout, "one\ntw");
+        capture.write(Stream::Stderr, "oops\n");
+        capture.write(Stream::Stdout, "o\n");
+        capture.write(Stream::Stdout, "three");
+        capture.flush();
+        assert_eq!(capture.stdout, "one\ntwo\nthree");
+        assert_eq!(capture.stderr, "oops\n");
+
+        drop(capture);
+        drop(runner);
+        let lines: Vec<(Stream, String)> = receiver.iter().collect();
+        assert_eq!(
+            lines,
+            vec![
+                (Stream::Stdout, "one".into()),
+                (Stream::Stderr, "oops".into()),
+                (Stream::Stdout, "two".into()),
+                (Stream::Stdout, "three".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_when_provided() {
+        let inventory = inventory();
+        let mock = MockTransport::new();
+        mock.add_file("/etc/motd", b"");
+        let target = inventory.target("alpha").unwrap();
+
+        let task = echo(&[("msg", "hi"), ("provides", "/etc/motd")]);
+        let result = run(&runner(&inventory, &mock), &task, &target);
+        assert!(result.skipped);
+        assert!(!result.changed);
+        assert!(!mock.commands().iter().any(|c| c.contains(REMOTE_SCRIPT)));
+    }
+
+    #[test]
+    fn skip_unless() {
+        let inventory = inventory();
+        let mock = MockTransport::new();
+        let target = inventory.target("alpha").unwrap();
+
+        let task = echo(&[("msg", "hi"), ("unless", "test -f /etc/motd")]);
+        assert!(run(&runner(&inventory, &mock), &task, &target).skipped);
+        let commands = mock.commands();
+        assert!(commands.contains(&in_workdir("'./._zap_unless'")));
+        assert!(!commands.iter().any(|c| c.contains(REMOTE_SCRIPT)));
+    }
+
+    #[test]
+    fn dry_run() {
+        let inventory = inventory();
+        let mock = MockTransport::new();
+        let target = inventory.target("alpha").unwrap();
+
+        let mut runner = runner(&inventory, &mock);
+        runner.dry_run = true;
+        let result = run(&runner, &echo(&[("msg", "hi")]), &target);
+        assert!(!result.changed && !result.skipped);
+        assert!(!mock
+            .operations()
+            .iter()
+            .any(|op| matches!(op, Operation::Put { .. })));
+    }
+
+    #[test]
+    fn run_with_files() {
+        let inventory = inventory();
+        let mock = MockTransport::new();
+        let target = inventory.target("alpha").unwrap();
+
+        let mut task = echo(&[("msg", "hi")]);
+        task.task.directory = PathBuf::from("../tasks");
+        task.task.files = vec![PathBuf::from("echo.ztask")];
+        assert!(run(&runner(&inventory, &mock), &task, &target).changed);
+
+        let bundle = mock
+            .file("/tmp/zap.mock/._zap_files.tar")
+            .expect("The bundle was not uploaded");
+        assert_eq!(
+            tar::Archive::new(bundle.as_slice())
+                .entries()
+                .unwrap()
+                .count(),
+            1
+        );
+        let commands = mock.commands();
+        assert_eq!(
+            commands[1],
+            "cd '/tmp/zap.mock' && tar -xf ._zap_files.tar && rm -f ._zap_files.tar"
+        );
+        assert_eq!(commands[2], in_workdir("'./._zap_command'"));
+    }
+
+    #[test]
+    fn run_with_escalation() {
+        let inventory = inventory();
+        let mock = MockTransport::new();
+        let target = inventory.target("alpha").unwrap();
+
+        let mut task = echo(&[("msg", "hi")]);
+        task.escalation.enabled = Some(true);
+        assert!(run(&runner(&inventory, &mock), &task, &target).changed);
+        let commands = mock.commands();
+        assert_eq!(commands[1], "chmod 711 '/tmp/zap.mock'");
+        assert_eq!(
+            commands[2],
+            format!("sudo -n -u 'root' -- {}", in_workdir("'./._zap_command'"))
+        );
+        assert_eq!(
+            mock.operations()[3],
+            Operation::Put {
+                path: Path::new(MOCK_TMPDIR).join(REMOTE_SCRIPT),
+                bytes: b"echo hi".to_vec(),
+                mode: 0o755,
+            }
+        );
+    }
+
+    #[test]
+    fn workdir_in_tmpdir() {
+        let mut inventory = inventory();
+        inventory.config.tmpdir = Some("/var/tmp".into());
+        let mock = MockTransport::new();
+        let target = inventory.target("alpha").unwrap();
+
+        run(&runner(&inventory, &mock), &echo(&[("msg", "hi")]), &target);
+        assert_eq!(mock.commands()[0], "mktemp -d '/var/tmp/zap.XXXXXX'");
+    }
+
+    #[test]
+    fn workdir_not_created() {
+        let inventory = inventory();
+        let mock = MockTransport::new();
+        mock.respond(
+            "mktemp",
+            Response {
+                status: 1,
+                stderr: "mktemp: Read-only file system".into(),
+                ..Default::default()
+            },
+        );
+        let target = inventory.target("alpha").unwrap();
+
+        let result = runner(&inventory, &mock).run(&echo(&[("msg", "hi")]), &target);
+        assert!(matches!(result, Err(TransportError::ExecutionFailed(_))));
+        assert_eq!(mock.commands().len(), 1);
+        assert_eq!(mock.operations().last(), Some(&Operation::Disconnect));
+    }
+
+    #[test]
+    fn run_group_reports_each_target() {
+        let inventory = inventory();
+        let mock = MockTransport::new();
+        mock.fail_connect(
+            "beta",
+            TransportError::ConnectionFailed("no route to host".into()),
+        );
+        let group = inventory.groups[0].clone();
+
+        let mut runner = runner(&inventory, &mock);
+        runner.parallel = 2;
+        let results = runner.run_group(&echo(&[("msg", "hi")]), &group);
+        assert!(matches!(results.get("alpha"), Some(Ok(r)) if r.changed));
+        assert!(matches!(
+            results.get("beta"),
+            Some(Err(TransportError::ConnectionFailed(_)))
+        ));
+        drop(runner);
+        assert_eq!(
+            mock.operations_on("alpha").last(),
+            Some(&Operation::Disconnect)
+        );
+        assert!(!mock
+            .operations_on("beta")
+            .iter()
+            .any(|op| matches!(op, Operation::Exec(_))));
+    }
+
+    #[test]
+    fn reuse_connections() {
+        let inventory = inventory();
+        let mock = MockTransport::new();
+        let target = inventory.target("alpha").unwrap();
+
+        let runner = runner(&inventory, &mock);
+        run(&runner, &echo(&[("msg", "hi")]), &target);
+        run(&runner, &echo(&[("msg", "bye")]), &target);
+        let connects = |mock: &MockTransport| {
+            mock.operations()
+                .iter()
+                .filter(|op| matches!(op, Operation::Connect(_)))
+                .count()
+        };
+        assert_eq!(connects(&mock), 1);
+        assert!(!mock.operations().contains(&Operation::Disconnect));
+
+        // Only the connection being reused is checked
+        let beta = inventory.target("beta").unwrap();
+        run(&runner, &echo(&[("msg", "hi")]), &beta);
+        let keepalives = mock
+            .operations_on("alpha")
+            .into_iter()
+            .filter(|op| *op == Operation::Keepalive)
+            .count();
+        assert_eq!(keepalives, 1);
+        assert_eq!(connects(&mock), 2);
+
+        // Connections waiting to be reused are kept alive meanwhile
+        send_keepalives(&runner.connections);
+        let keepalives = mock
+            .operations_on("beta")
+            .into_iter()
+            .filter(|op| *op == Operation::Keepalive)
+            .count();
+        assert_eq!(keepalives, 1);
+
+        // A dropped connection is noticed and replaced
+        mock.drop_connections();
+        run(&runner, &echo(&[("msg", "again")]), &target);
+        assert_eq!(connects(&mock), 3);
+
+        runner.disconnect();
+        let operations = mock.operations();
+        assert_eq!(
+            operations
+                .iter()
+                .filter(|op| **op == Operation::Disconnect)
+                .count(),
+            3
+        );
+        assert_eq!(operations.last(), Some(&Operation::Disconnect));
+    }
+
+    #[test]
+    fn retry_until_out_of_attempts() {
+        let inventory = inventory();
+        let mock = MockTransport::new();
+        mock.respond(REMOTE_SCRIPT, Response::exit(1));
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let runner = runner(&inventory, &mock).with_reporter(move |event| {
+            if let Event::Retry { attempt, delay, .. } = event {
+                sender.lock().unwrap().send((*attempt, *delay)).unwrap();
+            }
+        });
+        let target = inventory.target("alpha").unwrap();
+
+        let mut task = echo(&[("msg", "hi")]);
+        task.retries = 2;
+        task.retry_delay = Duration::from_millis(1);
+        let result = run(&runner, &task, &target);
+        assert_eq!(result.exit_code, 1);
+        assert_eq!(result.attempts, 3);
+
+        drop(runner);
+        let retries: Vec<(u32, Duration)> = receiver.iter().collect();
+        assert_eq!(
+            retries,
+            vec![(1, Duration::from_millis(1)), (2, Duration::from_millis(2))]
+        );
+        let scripts = mock
+            .commands()
+            .iter()
+            .filter(|c| c.contains(REMOTE_SCRIPT))
+            .count();
+        assert_eq!(scripts, 3);
+    }
+
+    #[test]
+    fn fail_until() {
+        let inventory = inventory();
+        let mock = MockTransport::new();
+        mock.respond(REMOTE_UNTIL, Response::exit(3));
+        let target = inventory.target("alpha").unwrap();
+
+        let mut task = echo(&[("msg", "hi")]);
+        task.until = Some("pkg info nginx".into());
+        let result = run(&runner(&inventory, &mock), &task, &target);
+        assert_eq!(result.exit_code, 3);
+        assert_eq!(result.attempts, 1);
+        assert!(!result.changed);
+        assert_eq!(
+            mock.file(&format!("{}/{}", MOCK_TMPDIR, REMOTE_UNTIL)),
+            Some(b"pkg info nginx".to_vec())
+        );
+    }
+
+    #[test]
+    fn kill_on_timeout() {
+        let inventory = inventory();
+        let mock = MockTransport::new();
+        mock.respond(
+            REMOTE_SCRIPT,
+            Response {
+                stdout: "working\n".into(),
+                stderr: "zap-pid 4242\nslowly\n".into(),
+                hangs: true,
+                ..Default::default()
+            },
+        );
+        let target = inventory.target("alpha").unwrap();
+
+        let mut task = echo(&[("msg", "hi")]);
+        task.timeout = Some(Duration::from_secs(1));
+        let result = run(&runner(&inventory, &mock), &task, &target);
+        assert!(result.timed_out);
+        assert!(!result.changed);
+        assert_eq!(result.exit_code, TIMEOUT_EXIT_CODE);
+        assert_eq!(result.stdout, "working\n");
+        assert_eq!(result.stderr, "slowly\n");
+
+        let commands = mock.commands();
+        assert_eq!(
+            commands[1],
+            in_workdir("echo zap-pid $$ >&2 && exec './._zap_command'")
+        );
+        assert!(commands[2].contains("kill -TERM -- -4242 2>/dev/null || kill -TERM $(tree 4242)"));
+    }
+
+    #[test]
+    fn kill_on_timeout_behind_pty() {
+        let inventory = inventory();
+        let mock = MockTransport::new();
+        mock.respond(
+            REMOTE_SCRIPT,
+            Response {
+                stdout: "Password:\r\nzap-pid 4242\r\nworking\r\n".into(),
+                hangs: true,
+                ..Default::default()
+            },
+        );
+        let target = inventory.target("alpha").unwrap();
+
+        let mut task = echo(&[("msg", "hi")]);
+        task.timeout = Some(Duration::from_secs(1));
+        task.escalation = Escalation {
+            enabled: Some(true),
+            method: Some(BecomeMethod::Doas),
+            password: Some("hunter2".into()),
+            ..Default::default()
+        };
+        assert!(task.escalation.prompts_on_tty());
+        let result = run(&runner(&inventory, &mock), &task, &target);
+        assert!(result.timed_out);
+        assert_eq!(result.stdout, "Password:\r\nworking\r\n");
+
+        let commands = mock.commands();
+        assert!(commands
+            .iter()
+            .any(|c| c.contains("kill -TERM -- -4242 2>/dev/null || kill -TERM $(tree 4242)")));
+    }
+
+    #[test]
+    fn pid_marker() {
+        let mut marker = PidMarker::default();
+        assert_eq!(
+            marker.filter("sudo: unable to resolve host\nzap-"),
+            "sudo: unable to resolve host\n"
+        );
+        assert_eq!(marker.filter("pid 12\nwarn"), "warn");
+        assert_eq!(marker.filter("ing\n"), "ing\n");
+        assert_eq!(marker.pid, Some(12));
+        assert_eq!(marker.finish(), "");
+    }
+}
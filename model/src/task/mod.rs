@@ -6,9 +6,12 @@ use pest::Parser;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 use url::Url;
 
+use crate::Escalation;
+
 #[derive(Parser)]
 #[grammar = "task.pest"]
 struct TaskParser;
@@ -56,9 +59,9 @@ impl Script {
     pub fn as_bytes(&self, parameters: Option<&HashMap<String, String>>) -> Option<Vec<u8>> {
         use handlebars::Handlebars;
 
-        if self.inline.is_some() && self.file.is_some() {
+        if let (Some(inline), Some(_)) = (&self.inline, &self.file) {
             warn!("Both inline and file structs are defined for this script, only file will be used!\n({})",
-            self.inline.as_ref().unwrap());
+            inline);
         }
 
         if let Some(path) = &self.file {
@@ -108,26 +111,108 @@ impl Script {
 pub struct Task {
     pub name: String,
     pub script: Script,
+    /// Whether, and how, the task should be run as another user on the target
+    pub escalation: Escalation,
+    /// Support files and directories uploaded alongside the script, relative
+    /// to the task's `directory`
+    pub files: Vec<PathBuf>,
+    /// The directory the task was loaded from
+    pub directory: PathBuf,
+    /// How long the task may run for before it is killed
+    pub timeout: Option<Duration>,
 }
 
+#[allow(clippy::result_large_err)]
 impl Task {
     pub fn new(name: &str) -> Self {
         Task {
             name: name.to_string(),
             script: Script::new(),
+            escalation: Escalation::default(),
+            files: vec![],
+            directory: PathBuf::new(),
+            timeout: None,
+        }
+    }
+
+    /**
+     * Pack the task's support files into a tarball, with the same layout
+     * they have next to the task, or None if the task has none
+     */
+    pub fn bundle(&self) -> std::io::Result<Option<Vec<u8>>> {
+        if self.files.is_empty() {
+            return Ok(None);
+        }
+
+        let mut builder = tar::Builder::new(vec![]);
+        for file in self.files.iter() {
+            let path = self.directory.join(file);
+            if path.is_dir() {
+                builder.append_dir_all(file, &path)?;
+            } else {
+                builder.append_path_with_name(&path, file)?;
+            }
+            debug!("Bundled {} for the task {}", path.display(), self.name);
         }
+        builder.into_inner().map(Some)
     }
 
     fn parse(parser: &mut Pairs<Rule>) -> Result<Self, PestError<Rule>> {
         let mut task: Option<Self> = None;
         let mut inline = None;
         let mut file = None;
+        let mut escalation = Escalation::default();
+        let mut files = vec![];
+        let mut timeout = None;
 
-        while let Some(parsed) = parser.next() {
+        for parsed in parser {
             match parsed.as_rule() {
                 Rule::identifier => {
                     task = Some(Task::new(parsed.as_str()));
                 }
+                Rule::become_enabled => {
+                    escalation.enabled = Some(parse_bool(&mut parsed.into_inner()));
+                }
+                Rule::become_method => {
+                    let span = parsed.as_span();
+                    let method = parse_str(&mut parsed.into_inner())?;
+                    escalation.method = Some(method.parse().map_err(|message| {
+                        PestError::new_from_span(ErrorVariant::CustomError { message }, span)
+                    })?);
+                }
+                Rule::become_user => {
+                    escalation.user = Some(parse_str(&mut parsed.into_inner())?);
+                }
+                Rule::timeout => {
+                    let span = parsed.as_span();
+                    let seconds = parsed.into_inner().as_str().parse().map_err(|_| {
+                        PestError::new_from_span(
+                            ErrorVariant::CustomError {
+                                message: "the timeout is too long".into(),
+                            },
+                            span,
+                        )
+                    })?;
+                    timeout = Some(Duration::from_secs(seconds));
+                }
+                Rule::files => {
+                    for pair in parsed.into_inner() {
+                        let span = pair.as_span();
+                        let file = PathBuf::from(parse_str(&mut pair.into_inner())?);
+                        if !is_bundleable(&file) {
+                            return Err(PestError::new_from_span(
+                                ErrorVariant::CustomError {
+                                    message: format!(
+                                        "`{}` must be a relative path within the task's directory",
+                                        file.display()
+                                    ),
+                                },
+                                span,
+                            ));
+                        }
+                        files.push(file);
+                    }
+                }
                 Rule::script => {
                     for pair in parsed.into_inner() {
                         match pair.as_rule() {
@@ -149,27 +234,28 @@ impl Task {
         if let Some(mut task) = task {
             task.script.inline = inline;
             task.script.file = file;
+            task.escalation = escalation;
+            task.files = files;
+            task.timeout = timeout;
 
-            return Ok(task);
+            Ok(task)
         } else {
-            return Err(PestError::new_from_pos(
+            Err(PestError::new_from_pos(
                 ErrorVariant::CustomError {
                     message: "Could not find a valid task definition".to_string(),
                 },
                 /* TODO: Find a better thing to report */
                 pest::Position::from_start(""),
-            ));
+            ))
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(buf: &str) -> Result<Self, PestError<Rule>> {
-        let mut parser = TaskParser::parse(Rule::taskfile, buf)?;
-        while let Some(parsed) = parser.next() {
-            match parsed.as_rule() {
-                Rule::task => {
-                    return Task::parse(&mut parsed.into_inner());
-                }
-                _ => {}
+        let parser = TaskParser::parse(Rule::taskfile, buf)?;
+        for parsed in parser {
+            if parsed.as_rule() == Rule::task {
+                return Task::parse(&mut parsed.into_inner());
             }
         }
 
@@ -183,7 +269,7 @@ impl Task {
 
     pub fn from_url(url: &str) -> Result<Self, PestError<Rule>> {
         if let Ok(url) = Url::parse(url) {
-            println!("UR: {:?}", url);
+            debug!("Loading the built-in task {:?}", url);
             if let Some(name) = url.host_str() {
                 // XXX: Temporary hard-coding see #5
                 let mut task = Task::new(name);
@@ -209,34 +295,60 @@ impl Task {
                 let mut contents = String::new();
 
                 if let Err(e) = file.read_to_string(&mut contents) {
-                    return Err(PestError::new_from_pos(
+                    Err(PestError::new_from_pos(
                         ErrorVariant::CustomError {
                             message: format!("{}", e),
                         },
                         pest::Position::from_start(""),
-                    ));
+                    ))
                 } else {
-                    return Self::from_str(&contents);
+                    let mut task = Self::from_str(&contents)?;
+                    if let Some(directory) = path.parent() {
+                        task.directory = directory.to_path_buf();
+                    }
+                    Ok(task)
                 }
             }
-            Err(e) => {
-                return Err(PestError::new_from_pos(
-                    ErrorVariant::CustomError {
-                        message: format!("{}", e),
-                    },
-                    pest::Position::from_start(""),
-                ));
-            }
+            Err(e) => Err(PestError::new_from_pos(
+                ErrorVariant::CustomError {
+                    message: format!("{}", e),
+                },
+                pest::Position::from_start(""),
+            )),
+        }
+    }
+}
+
+/**
+ * Support files have to stay within the task's directory, both locally and
+ * once they are unpacked on the target
+ */
+fn is_bundleable(file: &Path) -> bool {
+    file.components().count() > 0
+        && file
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/**
+ * Parser utility function to fish out the value of a bool Rule
+ */
+fn parse_bool(parser: &mut Pairs<Rule>) -> bool {
+    for parsed in parser.clone().flatten() {
+        if parsed.as_rule() == Rule::truthy {
+            return true;
         }
     }
+    false
 }
 
 /**
  * Parser utility function to fish out the _actual_ string value for something
  * that is looking like a string Rule
  */
+#[allow(clippy::result_large_err)]
 fn parse_str(parser: &mut Pairs<Rule>) -> Result<String, PestError<Rule>> {
-    while let Some(parsed) = parser.next() {
+    for parsed in parser {
         match parsed.as_rule() {
             Rule::string => {
                 return parse_str(&mut parsed.into_inner());
@@ -256,18 +368,19 @@ fn parse_str(parser: &mut Pairs<Rule>) -> Result<String, PestError<Rule>> {
             _ => {}
         }
     }
-    return Err(PestError::new_from_pos(
+    Err(PestError::new_from_pos(
         ErrorVariant::CustomError {
             message: "Could not parse out a string value".to_string(),
         },
         /* TODO: Find a better thing to report */
         pest::Position::from_start(""),
-    ));
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::escalation::BecomeMethod;
     #[test]
     fn parse_task_with_comments() {
         let buf = r#"
@@ -341,6 +454,96 @@ mod tests {
         assert_eq!(script.as_bytes(None).unwrap(), "env".as_bytes());
     }
 
+    #[test]
+    fn parse_task_with_escalation() {
+        let buf = r#"task Install {
+                become = true
+                become_method = 'doas'
+                become_user = 'www'
+                script {
+                    inline = 'pkg install -y nginx'
+                }
+            }"#;
+        let task = Task::from_str(buf).expect("Failed to parse the task");
+        assert!(task.escalation.is_enabled());
+        assert_eq!(task.escalation.method(), BecomeMethod::Doas);
+        assert_eq!(task.escalation.user(), "www");
+    }
+
+    #[test]
+    fn parse_task_with_timeout() {
+        let buf = r#"task Build {
+                timeout = 600
+                script {
+                    inline = 'make'
+                }
+            }"#;
+        let task = Task::from_str(buf).expect("Failed to parse the task");
+        assert_eq!(task.timeout, Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn parse_task_with_invalid_become_method() {
+        let buf = r#"task Install {
+                become_method = 'runas'
+                script {
+                    inline = 'pkg install -y nginx'
+                }
+            }"#;
+        assert!(Task::from_str(buf).is_err());
+    }
+
+    #[test]
+    fn parse_task_with_files() {
+        let buf = r#"task Nginx {
+                files = ['templates/nginx.conf', 'lib/',]
+                script {
+                    inline = './lib/install.sh'
+                }
+            }"#;
+        let task = Task::from_str(buf).expect("Failed to parse the task");
+        assert_eq!(
+            task.files,
+            vec![PathBuf::from("templates/nginx.conf"), PathBuf::from("lib/")]
+        );
+
+        let buf = r#"task Escape {
+                files = ['../secrets']
+                script {
+                    inline = 'cat ../secrets'
+                }
+            }"#;
+        assert!(Task::from_str(buf).is_err());
+    }
+
+    #[test]
+    fn bundle_files() {
+        let mut task = Task::from_path(&PathBuf::from("../tasks/echo.ztask")).unwrap();
+        assert_eq!(task.bundle().unwrap(), None);
+
+        task.files = vec![PathBuf::from("echo.ztask"), PathBuf::from("install/")];
+        let bundle = task.bundle().unwrap().expect("Failed to bundle the files");
+        let mut archive = tar::Archive::new(bundle.as_slice());
+        let mut names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().display().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "echo.ztask",
+                "install/",
+                "install/freebsd.ztask",
+                "install/zypper.ztask"
+            ]
+        );
+
+        task.files = vec![PathBuf::from("missing.sh")];
+        assert!(task.bundle().is_err());
+    }
+
     #[test]
     fn task_from_url() {
         let task = Task::from_url("zap://sh").expect("Failed to load task from URL");
@@ -0,0 +1,346 @@
+use crate::escalation::shell_quote;
+use crate::inventory::Target;
+use crate::transport::{stream_child, FileStat, Stream, Transport};
+use crate::{Escalation, TransportError};
+
+use log::*;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Child, Command, Output, Stdio};
+use std::time::Instant;
+
+/// The docker CLI, which must be on the PATH
+const DOCKER: &str = "docker";
+
+/**
+ * Docker runs tasks inside a running container with the docker CLI, so it
+ * works with whichever daemon or context the CLI is set up to talk to.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Docker {
+    container: Option<String>,
+    user: Option<String>,
+}
+
+/**
+ * The container to run in for the target: the one named in its docker config,
+ * otherwise its uri, either bare (`web`) or with a scheme (`docker://web`)
+ */
+fn container_for(target: &Target) -> Result<String, TransportError> {
+    let configured = target
+        .config
+        .as_ref()
+        .and_then(|c| c.docker.as_ref())
+        .and_then(|d| d.container.clone());
+    if let Some(container) = configured {
+        return Ok(container);
+    }
+    if !target.uri.contains("://") {
+        return Ok(target.uri.clone());
+    }
+
+    let uri = target
+        .parse_uri()
+        .map_err(|e| TransportError::GeneralError(format!("{}", e)))?;
+    if uri.scheme != "docker" {
+        return Err(TransportError::GeneralError(format!(
+            "the docker transport cannot connect to a `{}` uri",
+            uri.scheme
+        )));
+    }
+    Ok(uri.host)
+}
+
+/**
+ * Parse the output of the stat check, `<size> <octal mode> <file type>` or
+ * `missing`
+ */
+fn parse_stat(output: &str) -> Result<Option<FileStat>, String> {
+    let output = output.trim();
+    if output == "missing" {
+        return Ok(None);
+    }
+    let mut fields = output.splitn(3, ' ');
+    let (size, mode, kind) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(size), Some(mode), Some(kind)) => (size, mode, kind),
+        _ => return Err(format!("unexpected output from stat: `{}`", output)),
+    };
+    Ok(Some(FileStat {
+        size: size
+            .parse()
+            .map_err(|_| format!("invalid size from stat: `{}`", size))?,
+        mode: i32::from_str_radix(mode, 8)
+            .map_err(|_| format!("invalid mode from stat: `{}`", mode))?,
+        is_dir: kind == "directory",
+    }))
+}
+
+impl Docker {
+    fn container(&self) -> Result<&str, TransportError> {
+        self.container
+            .as_deref()
+            .ok_or(TransportError::NotConnected)
+    }
+
+    /**
+     * Start the docker CLI with the given arguments, feeding it `stdin` if
+     * given, with its stdout and stderr piped back
+     */
+    fn spawn(&self, args: &[&str], stdin: Option<&[u8]>) -> Result<Child, TransportError> {
+        trace!("Running docker {:?}", args);
+        let mut child = Command::new(DOCKER)
+            .args(args)
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                TransportError::ConnectionFailed(format!("could not run {}: {}", DOCKER, e))
+            })?;
+
+        if let (Some(bytes), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            pipe.write_all(bytes).map_err(|e| {
+                TransportError::ExecutionFailed(format!("failed to write to {}: {}", DOCKER, e))
+            })?;
+        }
+        Ok(child)
+    }
+
+    /**
+     * Run the docker CLI with the given arguments, feeding it `stdin` if given
+     */
+    fn docker(&self, args: &[&str], stdin: Option<&[u8]>) -> Result<Output, TransportError> {
+        self.spawn(args, stdin)?
+            .wait_with_output()
+            .map_err(|e| TransportError::ExecutionFailed(format!("{}", e)))
+    }
+
+    /**
+     * Run the shell script inside the container
+     */
+    fn sh(&self, script: &str, stdin: Option<&[u8]>) -> Result<Output, TransportError> {
+        self.docker(&self.sh_args(script, stdin.is_some())?, stdin)
+    }
+
+    /**
+     * The arguments for running the shell script inside the container
+     */
+    fn sh_args<'a>(&'a self, script: &'a str, stdin: bool) -> Result<Vec<&'a str>, TransportError> {
+        let container = self.container()?;
+        let mut args = vec!["exec"];
+        if stdin {
+            args.push("-i");
+        }
+        if let Some(user) = &self.user {
+            args.extend(&["-u", user]);
+        }
+        args.extend(&[container, "/bin/sh", "-c", script]);
+        Ok(args)
+    }
+
+    /**
+     * Like sh(), but a non-zero exit is an error built by `failed` from what
+     * the script wrote to stderr
+     */
+    fn sh_ok<F>(
+        &self,
+        script: &str,
+        stdin: Option<&[u8]>,
+        failed: F,
+    ) -> Result<Output, TransportError>
+    where
+        F: Fn(String) -> TransportError,
+    {
+        let output = self.sh(script, stdin)?;
+        if !output.status.success() {
+            return Err(failed(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        Ok(output)
+    }
+
+    /**
+     * Run the command in the container, giving up on it if the deadline
+     * passes
+     */
+    fn run(
+        &mut self,
+        command: &str,
+        escalation: &Escalation,
+        deadline: Option<Instant>,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Option<i32>, TransportError> {
+        if escalation.prompts_on_tty() {
+            return Err(TransportError::ExecutionFailed(
+                "doas and su only read a password from a terminal, which the docker transport \
+                 doesn't provide"
+                    .into(),
+            ));
+        }
+        let password = escalation.password().map(|p| format!("{}\n", p));
+        let stdin = password.as_ref().map(|p| p.as_bytes());
+        let wrapped = escalation.wrap(command);
+        let mut child = self.spawn(&self.sh_args(&wrapped, stdin.is_some())?, stdin)?;
+        let status = stream_child(&mut child, deadline, output)?;
+        Ok(status.map(|status| status.code().unwrap_or(-1)))
+    }
+}
+
+impl Transport for Docker {
+    fn connect(&mut self, target: &Target) -> Result<(), TransportError> {
+        if self.container.is_some() {
+            return Ok(());
+        }
+        let container = container_for(target)?;
+        let inspect = self.docker(
+            &["inspect", "--format", "{{.State.Running}}", &container],
+            None,
+        )?;
+        if String::from_utf8_lossy(&inspect.stdout).trim() != "true" {
+            return Err(TransportError::ConnectionFailed(format!(
+                "container `{}` is not running: {}",
+                container,
+                String::from_utf8_lossy(&inspect.stderr).trim()
+            )));
+        }
+
+        self.container = Some(container);
+        self.user = target
+            .config
+            .as_ref()
+            .and_then(|c| c.docker.as_ref())
+            .and_then(|d| d.user.clone());
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.container = None;
+    }
+
+    fn exec(
+        &mut self,
+        command: &str,
+        escalation: &Escalation,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<i32, TransportError> {
+        self.run(command, escalation, None, output)
+            .map(|status| status.unwrap_or(-1))
+    }
+
+    /**
+     * Only the `docker exec` client is killed when the deadline passes, the
+     * command carries on inside the container
+     */
+    fn exec_until(
+        &mut self,
+        command: &str,
+        escalation: &Escalation,
+        deadline: Instant,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Option<i32>, TransportError> {
+        self.run(command, escalation, Some(deadline), output)
+    }
+
+    fn put(&mut self, remote_path: &Path, bytes: &[u8], mode: i32) -> Result<(), TransportError> {
+        let path = shell_quote(&remote_path.to_string_lossy());
+        let script = format!("cat > {} && chmod {:o} {}", path, mode, path);
+        self.sh_ok(&script, Some(bytes), |e| {
+            TransportError::TransferFailed(format!("{}: {}", remote_path.display(), e))
+        })?;
+        Ok(())
+    }
+
+    fn get(&mut self, remote_path: &Path) -> Result<Vec<u8>, TransportError> {
+        let script = format!("cat {}", shell_quote(&remote_path.to_string_lossy()));
+        let output = self.sh_ok(&script, None, |e| {
+            TransportError::TransferFailed(format!("{}: {}", remote_path.display(), e))
+        })?;
+        Ok(output.stdout)
+    }
+
+    fn stat(&mut self, path: &Path) -> Result<Option<FileStat>, TransportError> {
+        let quoted = shell_quote(&path.to_string_lossy());
+        let script = format!(
+            "if [ -e {} ]; then stat -c '%s %a %F' {}; else echo missing; fi",
+            quoted, quoted
+        );
+        let check_failed = |e: String| {
+            TransportError::GeneralError(format!("failed to check {}: {}", path.display(), e))
+        };
+        let output = self.sh_ok(&script, None, check_failed)?;
+        parse_stat(&String::from_utf8_lossy(&output.stdout)).map_err(check_failed)
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<(), TransportError> {
+        let script = format!("rm -f {}", shell_quote(&path.to_string_lossy()));
+        self.sh_ok(&script, None, |e| {
+            TransportError::GeneralError(format!("failed to remove {}: {}", path.display(), e))
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory::{Config, DockerConfig};
+
+    fn target(uri: &str) -> Target {
+        Target {
+            name: "test".into(),
+            uri: uri.into(),
+            config: None,
+        }
+    }
+
+    #[test]
+    fn container_from_uri() {
+        assert_eq!(container_for(&target("target1")).unwrap(), "target1");
+        assert_eq!(
+            container_for(&target("docker://target1")).unwrap(),
+            "target1"
+        );
+        assert!(container_for(&target("ssh://target1")).is_err());
+    }
+
+    #[test]
+    fn container_from_config() {
+        let mut target = target("docker://web");
+        let config: Config = serde_yaml::from_str("transport: docker").unwrap();
+        target.config = Some(Config {
+            docker: Some(DockerConfig {
+                container: Some("3f4e8a1c2b9d".into()),
+                user: None,
+            }),
+            ..config
+        });
+        assert_eq!(container_for(&target).unwrap(), "3f4e8a1c2b9d");
+    }
+
+    #[test]
+    fn parse_stat_output() {
+        assert_eq!(parse_stat("missing\n"), Ok(None));
+        assert_eq!(
+            parse_stat("12 644 regular file\n"),
+            Ok(Some(FileStat {
+                size: 12,
+                mode: 0o644,
+                is_dir: false
+            }))
+        );
+        assert_eq!(
+            parse_stat("4096 755 directory"),
+            Ok(Some(FileStat {
+                size: 4096,
+                mode: 0o755,
+                is_dir: true
+            }))
+        );
+        assert!(parse_stat("bogus").is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use crate::escalation::shell_quote;
+use crate::inventory::{self, Target};
+use crate::transport::{FileStat, Stream, Transport};
+use crate::{Escalation, TransportError};
+
+use log::*;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/**
+ * What the commands are confined to on the host
+ */
+#[derive(Clone, Debug, PartialEq)]
+enum Confinement {
+    /// A FreeBSD jail, entered with jexec
+    Jail(String),
+    /// A directory, entered with chroot
+    Chroot,
+}
+
+/**
+ * Jail runs tasks inside a FreeBSD jail or a chroot, on a host which is
+ * reached with another transport.
+ *
+ * Files are placed into the jail's root directory from the host, and commands
+ * are run with `jexec` or `chroot`, so the connection to the host must be
+ * allowed to do both, e.g. by logging in as root or with `become`.
+ */
+pub struct Jail {
+    inner: Box<dyn Transport>,
+    confinement: Option<Confinement>,
+    /// The jail's root directory on the host
+    root: PathBuf,
+}
+
+impl Jail {
+    /**
+     * Wrap the transport which reaches the host the jail is on
+     */
+    pub fn new(inner: Box<dyn Transport>) -> Self {
+        Self {
+            inner,
+            confinement: None,
+            root: PathBuf::from("/"),
+        }
+    }
+
+    /**
+     * Run the command on the host and collect its stdout, failing if it does
+     * not exit successfully
+     */
+    fn capture(&mut self, command: &str) -> Result<String, String> {
+        let (mut stdout, mut stderr) = (String::new(), String::new());
+        let status = self
+            .inner
+            .exec(
+                command,
+                &Escalation::default(),
+                &mut |stream, text| match stream {
+                    Stream::Stdout => stdout.push_str(text),
+                    Stream::Stderr => stderr.push_str(text),
+                },
+            )
+            .map_err(|e| format!("{}", e))?;
+        if status != 0 {
+            return Err(stderr.trim().to_string());
+        }
+        Ok(stdout.trim().to_string())
+    }
+
+    /**
+     * Where the path inside the jail is on the host, relative paths are
+     * relative to the jail's root
+     */
+    fn host_path(&self, path: &Path) -> Result<PathBuf, TransportError> {
+        if self.confinement.is_none() {
+            return Err(TransportError::NotConnected);
+        }
+        Ok(self.root.join(path.strip_prefix("/").unwrap_or(path)))
+    }
+
+    /**
+     * Wrap the command so that it's run inside the jail
+     */
+    fn wrap(&self, command: &str) -> Result<String, TransportError> {
+        let script = shell_quote(command);
+        match &self.confinement {
+            Some(Confinement::Jail(name)) => {
+                Ok(format!("jexec {} /bin/sh -c {}", shell_quote(name), script))
+            }
+            Some(Confinement::Chroot) => Ok(format!(
+                "chroot {} /bin/sh -c {}",
+                shell_quote(&self.root.to_string_lossy()),
+                script
+            )),
+            None => Err(TransportError::NotConnected),
+        }
+    }
+
+    /**
+     * Find the jail or chroot the target is configured with, and its root
+     * directory on the host
+     */
+    fn locate(&mut self, target: &Target) -> Result<(), TransportError> {
+        let config = target.config.as_ref();
+
+        if target.transport() == inventory::Transport::Chroot {
+            let root = config.and_then(|c| c.chroot.clone()).ok_or_else(|| {
+                TransportError::GeneralError(
+                    "the chroot transport needs the `chroot` directory to run in".into(),
+                )
+            })?;
+            match self.inner.stat(Path::new(&root))? {
+                Some(stat) if stat.is_dir => {}
+                _ => {
+                    return Err(TransportError::ConnectionFailed(format!(
+                        "{} is not a directory on `{}`",
+                        root, target.name
+                    )))
+                }
+            }
+            self.root = PathBuf::from(root);
+            self.confinement = Some(Confinement::Chroot);
+            return Ok(());
+        }
+
+        let name = config.and_then(|c| c.jail.clone()).ok_or_else(|| {
+            TransportError::GeneralError("the jail transport needs the name of a `jail`".into())
+        })?;
+        let root = self
+            .capture(&format!("jls -j {} path", shell_quote(&name)))
+            .map_err(|e| {
+                TransportError::ConnectionFailed(format!(
+                    "jail `{}` is not running on `{}`: {}",
+                    name, target.name, e
+                ))
+            })?;
+        self.root = PathBuf::from(root);
+        self.confinement = Some(Confinement::Jail(name));
+        Ok(())
+    }
+}
+
+impl Transport for Jail {
+    fn connect(&mut self, target: &Target) -> Result<(), TransportError> {
+        if self.confinement.is_some() {
+            return Ok(());
+        }
+        self.inner.connect(target)?;
+        self.locate(target)?;
+        debug!("Running inside {}", self.root.display());
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.confinement = None;
+        self.inner.disconnect();
+    }
+
+    fn keepalive(&mut self) -> Result<(), TransportError> {
+        self.inner.keepalive()
+    }
+
+    fn exec(
+        &mut self,
+        command: &str,
+        escalation: &Escalation,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<i32, TransportError> {
+        let wrapped = self.wrap(command)?;
+        self.inner.exec(&wrapped, escalation, output)
+    }
+
+    fn exec_until(
+        &mut self,
+        command: &str,
+        escalation: &Escalation,
+        deadline: Instant,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Option<i32>, TransportError> {
+        let wrapped = self.wrap(command)?;
+        self.inner
+            .exec_until(&wrapped, escalation, deadline, output)
+    }
+
+    fn put(&mut self, remote_path: &Path, bytes: &[u8], mode: i32) -> Result<(), TransportError> {
+        let path = self.host_path(remote_path)?;
+        self.inner.put(&path, bytes, mode)
+    }
+
+    fn get(&mut self, remote_path: &Path) -> Result<Vec<u8>, TransportError> {
+        let path = self.host_path(remote_path)?;
+        self.inner.get(&path)
+    }
+
+    fn stat(&mut self, path: &Path) -> Result<Option<FileStat>, TransportError> {
+        let path = self.host_path(path)?;
+        self.inner.stat(&path)
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<(), TransportError> {
+        let path = self.host_path(path)?;
+        self.inner.remove(&path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::local::Local;
+
+    fn jail(confinement: Confinement, root: &str) -> Jail {
+        let mut jail = Jail::new(Box::new(Local::default()));
+        jail.confinement = Some(confinement);
+        jail.root = PathBuf::from(root);
+        jail
+    }
+
+    #[test]
+    fn host_paths() {
+        let jail = jail(Confinement::Jail("www".into()), "/usr/jails/www");
+        assert_eq!(
+            jail.host_path(Path::new("/tmp/zap.abc123/._zap_command"))
+                .unwrap(),
+            PathBuf::from("/usr/jails/www/tmp/zap.abc123/._zap_command")
+        );
+        assert_eq!(
+            jail.host_path(Path::new("/usr/local/sbin/nginx")).unwrap(),
+            PathBuf::from("/usr/jails/www/usr/local/sbin/nginx")
+        );
+    }
+
+    #[test]
+    fn wrap_jexec() {
+        let jail = jail(Confinement::Jail("www".into()), "/usr/jails/www");
+        assert_eq!(
+            jail.wrap("cd '/tmp/zap.abc123' && ./._zap_command")
+                .unwrap(),
+            r"jexec 'www' /bin/sh -c 'cd '\''/tmp/zap.abc123'\'' && ./._zap_command'"
+        );
+    }
+
+    #[test]
+    fn wrap_chroot() {
+        let jail = jail(Confinement::Chroot, "/srv/root");
+        assert_eq!(
+            jail.wrap("true").unwrap(),
+            r"chroot '/srv/root' /bin/sh -c 'true'"
+        );
+    }
+
+    #[test]
+    fn not_connected() {
+        let jail = Jail::new(Box::new(Local::default()));
+        assert!(jail.host_path(Path::new("file")).is_err());
+        assert!(jail.wrap("true").is_err());
+    }
+}
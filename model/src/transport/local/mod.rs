@@ -0,0 +1,317 @@
+use crate::escalation::Prompted;
+use crate::inventory::Target;
+use crate::transport::{stream_child, FileStat, Stream, Transport};
+use crate::{Escalation, TransportError};
+
+use log::*;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+/**
+ * Local runs tasks on the machine zap is running on, with `/bin/sh`.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Local {
+    connected: bool,
+}
+
+impl Local {
+    fn ensure_connected(&self) -> Result<(), TransportError> {
+        if self.connected {
+            Ok(())
+        } else {
+            Err(TransportError::NotConnected)
+        }
+    }
+
+    /**
+     * Run the command, giving up on it if the deadline passes
+     */
+    fn run(
+        &mut self,
+        command: &str,
+        escalation: &Escalation,
+        deadline: Option<Instant>,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Option<i32>, TransportError> {
+        if escalation.prompts_on_tty() {
+            return Err(TransportError::ExecutionFailed(
+                "doas and su only read a password from a terminal, which the local transport \
+                 doesn't have"
+                    .into(),
+            ));
+        }
+        self.ensure_connected()?;
+        let password = escalation.password();
+        let wrapped = escalation.wrap(command);
+        trace!("Executing: {}", wrapped);
+
+        let mut command = Command::new("/bin/sh");
+        command
+            .arg("-c")
+            .arg(&wrapped)
+            .stdin(if password.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if deadline.is_some() {
+            // Whatever the command starts can then be killed along with it
+            command.process_group(0);
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|e| TransportError::ExecutionFailed(format!("{}", e)))?;
+
+        // stdin is closed once dropped, so a rejected password fails the
+        // command rather than leaving sudo waiting, and a command which reads
+        // stdin gets nothing
+        let mut stdin = child.stdin.take();
+        let mut prompt = escalation.password_prompt();
+        let streamed = stream_child(&mut child, deadline, &mut |stream, text| {
+            let prompt = match prompt.as_mut() {
+                Some(prompt) => prompt,
+                None => return output(stream, text),
+            };
+            match prompt.watch(stream, text, output) {
+                Some(Prompted::Asked) => {
+                    if let (Some(mut stdin), Some(password)) = (stdin.take(), password) {
+                        if let Err(err) = writeln!(stdin, "{}", password) {
+                            warn!("Failed to send the password: {}", err);
+                        }
+                    }
+                }
+                Some(Prompted::Started) => stdin = None,
+                None => {}
+            }
+        });
+        if let Some(prompt) = prompt.as_mut() {
+            prompt.finish(output);
+        }
+
+        let status = match streamed? {
+            Some(status) => status,
+            None => return Ok(None),
+        };
+
+        // A process killed by a signal has no exit code, report it the way a
+        // shell would
+        Ok(Some(
+            status
+                .code()
+                .or_else(|| status.signal().map(|signal| 128 + signal))
+                .unwrap_or(-1),
+        ))
+    }
+}
+
+impl Transport for Local {
+    fn connect(&mut self, _target: &Target) -> Result<(), TransportError> {
+        self.connected = true;
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.connected = false;
+    }
+
+    fn exec(
+        &mut self,
+        command: &str,
+        escalation: &Escalation,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<i32, TransportError> {
+        self.run(command, escalation, None, output)
+            .map(|status| status.unwrap_or(-1))
+    }
+
+    fn exec_until(
+        &mut self,
+        command: &str,
+        escalation: &Escalation,
+        deadline: Instant,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Option<i32>, TransportError> {
+        self.run(command, escalation, Some(deadline), output)
+    }
+
+    fn put(&mut self, remote_path: &Path, bytes: &[u8], mode: i32) -> Result<(), TransportError> {
+        self.ensure_connected()?;
+        let transfer_failed = |e: std::io::Error| {
+            TransportError::TransferFailed(format!("{}: {}", remote_path.display(), e))
+        };
+        fs::write(remote_path, bytes).map_err(transfer_failed)?;
+        fs::set_permissions(remote_path, fs::Permissions::from_mode(mode as u32))
+            .map_err(transfer_failed)
+    }
+
+    fn get(&mut self, remote_path: &Path) -> Result<Vec<u8>, TransportError> {
+        self.ensure_connected()?;
+        fs::read(remote_path).map_err(|e| {
+            TransportError::TransferFailed(format!("{}: {}", remote_path.display(), e))
+        })
+    }
+
+    fn stat(&mut self, path: &Path) -> Result<Option<FileStat>, TransportError> {
+        self.ensure_connected()?;
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(Some(FileStat {
+                size: metadata.len(),
+                mode: (metadata.permissions().mode() & 0o7777) as i32,
+                is_dir: metadata.is_dir(),
+            })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(TransportError::GeneralError(format!(
+                "failed to check {}: {}",
+                path.display(),
+                e
+            ))),
+        }
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<(), TransportError> {
+        self.ensure_connected()?;
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(
+                TransportError::GeneralError(format!("failed to remove {}: {}", path.display(), e)),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::escalation::shell_quote;
+    use std::path::PathBuf;
+
+    fn connected() -> Local {
+        let target = Target {
+            name: "local".into(),
+            uri: "localhost".into(),
+            config: None,
+        };
+        let mut local = Local::default();
+        local.connect(&target).expect("Failed to connect");
+        local
+    }
+
+    /**
+     * A fresh directory for the test to put its files in
+     */
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zap-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).expect("Failed to create a scratch directory");
+        dir
+    }
+
+    #[test]
+    fn exec_script() {
+        let mut local = connected();
+        let dir = scratch("exec-script");
+        let script = dir.join("script");
+        local
+            .put(&script, b"#!/bin/sh\necho hello\nexit 3\n", 0o700)
+            .unwrap();
+
+        let mut stdout = String::new();
+        let command = shell_quote(&script.to_string_lossy());
+        let status = local
+            .exec(&command, &Escalation::default(), &mut |stream, text| {
+                if stream == Stream::Stdout {
+                    stdout.push_str(text);
+                }
+            })
+            .expect("Failed to exec");
+        assert_eq!(status, 3);
+        assert_eq!(stdout, "hello\n");
+        local.disconnect();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exec_streams_both() {
+        let mut local = connected();
+        let mut seen = vec![];
+        // Enough stderr to fill a pipe before anything is written to stdout
+        let status = local
+            .exec(
+                "head -c 200000 /dev/zero >&2; echo done",
+                &Escalation::default(),
+                &mut |stream, text| seen.push((stream, text.len())),
+            )
+            .expect("Failed to exec");
+        assert_eq!(status, 0);
+
+        let total = |wanted| -> usize {
+            seen.iter()
+                .filter(|(stream, _)| *stream == wanted)
+                .map(|(_, len)| len)
+                .sum()
+        };
+        assert_eq!(total(Stream::Stderr), 200000);
+        assert_eq!(total(Stream::Stdout), 5);
+        local.disconnect();
+    }
+
+    #[test]
+    fn exec_until_deadline() {
+        let mut local = connected();
+        let started = Instant::now();
+        let mut stdout = String::new();
+        let status = local
+            .exec_until(
+                "echo started; sleep 10",
+                &Escalation::default(),
+                started + std::time::Duration::from_millis(500),
+                &mut |_, text| stdout.push_str(text),
+            )
+            .expect("Failed to exec");
+        assert_eq!(status, None);
+        assert_eq!(stdout, "started\n");
+        assert!(started.elapsed().as_secs() < 5);
+        local.disconnect();
+    }
+
+    #[test]
+    fn put_stat_remove() {
+        let mut local = connected();
+        let dir = scratch("put-stat-remove");
+        let path = &dir.join("file");
+        assert_eq!(local.stat(path).unwrap(), None);
+
+        local.put(path, b"zap", 0o400).unwrap();
+        assert_eq!(
+            local.stat(path).unwrap(),
+            Some(FileStat {
+                size: 3,
+                mode: 0o400,
+                is_dir: false
+            })
+        );
+        assert_eq!(local.get(path).unwrap(), b"zap");
+
+        local.remove(path).unwrap();
+        assert_eq!(local.stat(path).unwrap(), None);
+        local.disconnect();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exec_without_connecting() {
+        let mut local = Local::default();
+        match local.exec("true", &Escalation::default(), &mut |_, _| {}) {
+            Err(TransportError::NotConnected) => {}
+            other => panic!("Expected NotConnected, got {:?}", other),
+        }
+    }
+}
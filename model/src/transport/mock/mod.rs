@@ -0,0 +1,351 @@
+use crate::inventory::Target;
+use crate::transport::{FileStat, Stream, Transport};
+use crate::{Escalation, TransportError};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+/// The directory `mktemp -d` creates, unless it's given a scripted response
+pub const MOCK_TMPDIR: &str = "/tmp/zap.mock";
+
+/**
+ * An Operation is something which was asked of the MockTransport
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    /// Connecting to the named target
+    Connect(String),
+    Disconnect,
+    Keepalive,
+    /// Running the command, after it was wrapped for escalation
+    Exec(String),
+    Put {
+        path: PathBuf,
+        bytes: Vec<u8>,
+        mode: i32,
+    },
+    Get(PathBuf),
+    Stat(PathBuf),
+    Remove(PathBuf),
+}
+
+/**
+ * The scripted outcome of running a command
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Response {
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+    /// The command never finishes on its own: `exec_until` runs out of time
+    /// after passing along the output, and `exec` reports an exit status of -1
+    pub hangs: bool,
+}
+
+impl Response {
+    pub fn exit(status: i32) -> Self {
+        Self {
+            status,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Every operation, along with the target it was performed on
+    operations: Vec<(Option<String>, Operation)>,
+    /// Responses for commands containing the pattern, the first match wins
+    responses: Vec<(String, Response)>,
+    files: HashMap<PathBuf, (Vec<u8>, i32)>,
+    connect_failures: HashMap<String, TransportError>,
+    /// Bumped whenever every connection is dropped
+    generation: usize,
+}
+
+/**
+ * MockTransport pretends to be a target, recording everything that is asked
+ * of it so that tests can check exactly what a task or plan would do.
+ *
+ * Clones share their recordings, responses and files, so a clone can be
+ * handed to the Runner for each target while the original is kept around to
+ * make assertions with:
+ *
+ * ```
+ * use zap_model::transport::mock::MockTransport;
+ *
+ * let mock = MockTransport::new();
+ * let transports = mock.clone();
+ * // runner.with_transports(move |_| Box::new(transports.clone()));
+ * assert!(mock.operations().is_empty());
+ * ```
+ *
+ * Commands which have no scripted response exit successfully with no output,
+ * except for `mktemp -d` which prints [MOCK_TMPDIR]. Once connections have
+ * been dropped, commands and keepalives fail until the clone reconnects.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<State>>,
+    /// The target this clone is connected to
+    target: Option<String>,
+    /// Which generation of connections this clone's belongs to
+    generation: usize,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // A test which panicked while holding the lock has failed already
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn record(&self, operation: Operation) {
+        self.state()
+            .operations
+            .push((self.target.clone(), operation));
+    }
+
+    /**
+     * Respond to any command containing `pattern` with the given response,
+     * patterns are checked in the order they were added
+     */
+    pub fn respond(&self, pattern: &str, response: Response) {
+        self.state().responses.push((pattern.to_string(), response));
+    }
+
+    /**
+     * Fail to connect to the named target with the given error
+     */
+    pub fn fail_connect(&self, target: &str, error: TransportError) {
+        self.state()
+            .connect_failures
+            .insert(target.to_string(), error);
+    }
+
+    /**
+     * Drop every connection which is currently open, as though the network
+     * went away, so that they fail when they are next used
+     */
+    pub fn drop_connections(&self) {
+        self.state().generation += 1;
+    }
+
+    fn check_connection(&self) -> Result<(), TransportError> {
+        if self.generation == self.state().generation {
+            Ok(())
+        } else {
+            Err(TransportError::ConnectionFailed(
+                "the connection was dropped".into(),
+            ))
+        }
+    }
+
+    /**
+     * Pretend that the file exists on the target
+     */
+    pub fn add_file(&self, path: &str, bytes: &[u8]) {
+        self.state()
+            .files
+            .insert(PathBuf::from(path), (bytes.to_vec(), 0o644));
+    }
+
+    /**
+     * The contents of the file, if it has been uploaded or added
+     */
+    pub fn file(&self, path: &str) -> Option<Vec<u8>> {
+        self.state()
+            .files
+            .get(Path::new(path))
+            .map(|(bytes, _)| bytes.clone())
+    }
+
+    /**
+     * Everything which has been asked of the transport so far, in order
+     */
+    pub fn operations(&self) -> Vec<Operation> {
+        self.state()
+            .operations
+            .iter()
+            .map(|(_, operation)| operation.clone())
+            .collect()
+    }
+
+    /**
+     * Everything which has been asked of the transport for the named target
+     */
+    pub fn operations_on(&self, target: &str) -> Vec<Operation> {
+        self.state()
+            .operations
+            .iter()
+            .filter(|(on, _)| on.as_deref() == Some(target))
+            .map(|(_, operation)| operation.clone())
+            .collect()
+    }
+
+    /**
+     * Just the commands which have been run, in order
+     */
+    pub fn commands(&self) -> Vec<String> {
+        self.operations()
+            .into_iter()
+            .filter_map(|operation| match operation {
+                Operation::Exec(command) => Some(command),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Transport for MockTransport {
+    fn connect(&mut self, target: &Target) -> Result<(), TransportError> {
+        self.target = Some(target.name.clone());
+        let generation = self.state().generation;
+        self.generation = generation;
+        self.record(Operation::Connect(target.name.clone()));
+        match self.state().connect_failures.get(&target.name) {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.record(Operation::Disconnect);
+    }
+
+    fn keepalive(&mut self) -> Result<(), TransportError> {
+        self.record(Operation::Keepalive);
+        self.check_connection()
+    }
+
+    fn exec(
+        &mut self,
+        command: &str,
+        escalation: &Escalation,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<i32, TransportError> {
+        self.exec_until(command, escalation, Instant::now(), output)
+            .map(|status| status.unwrap_or(-1))
+    }
+
+    fn exec_until(
+        &mut self,
+        command: &str,
+        escalation: &Escalation,
+        _deadline: Instant,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Option<i32>, TransportError> {
+        let command = escalation.wrap(command);
+        self.record(Operation::Exec(command.clone()));
+        self.check_connection()?;
+
+        let response = self
+            .state()
+            .responses
+            .iter()
+            .find(|(pattern, _)| command.contains(pattern.as_str()))
+            .map(|(_, response)| response.clone())
+            .unwrap_or_else(|| {
+                if command.starts_with("mktemp -d") {
+                    Response {
+                        stdout: format!("{}\n", MOCK_TMPDIR),
+                        ..Default::default()
+                    }
+                } else {
+                    Response::default()
+                }
+            });
+        if !response.stderr.is_empty() {
+            output(Stream::Stderr, &response.stderr);
+        }
+        if !response.stdout.is_empty() {
+            output(Stream::Stdout, &response.stdout);
+        }
+        if response.hangs {
+            return Ok(None);
+        }
+        Ok(Some(response.status))
+    }
+
+    fn put(&mut self, remote_path: &Path, bytes: &[u8], mode: i32) -> Result<(), TransportError> {
+        self.record(Operation::Put {
+            path: remote_path.to_path_buf(),
+            bytes: bytes.to_vec(),
+            mode,
+        });
+        self.state()
+            .files
+            .insert(remote_path.to_path_buf(), (bytes.to_vec(), mode));
+        Ok(())
+    }
+
+    fn get(&mut self, remote_path: &Path) -> Result<Vec<u8>, TransportError> {
+        self.record(Operation::Get(remote_path.to_path_buf()));
+        self.state()
+            .files
+            .get(remote_path)
+            .map(|(bytes, _)| bytes.clone())
+            .ok_or_else(|| {
+                TransportError::TransferFailed(format!("{}: no such file", remote_path.display()))
+            })
+    }
+
+    fn stat(&mut self, path: &Path) -> Result<Option<FileStat>, TransportError> {
+        self.record(Operation::Stat(path.to_path_buf()));
+        Ok(self.state().files.get(path).map(|(bytes, mode)| FileStat {
+            size: bytes.len() as u64,
+            mode: *mode,
+            is_dir: false,
+        }))
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<(), TransportError> {
+        self.record(Operation::Remove(path.to_path_buf()));
+        self.state().files.remove(path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripted_responses() {
+        let mut mock = MockTransport::new();
+        mock.respond("pkg info", Response::exit(1));
+        mock.respond(
+            "hostname",
+            Response {
+                stdout: "gopher\n".into(),
+                ..Default::default()
+            },
+        );
+
+        let mut stdout = String::new();
+        let mut collect = |_: Stream, text: &str| stdout.push_str(text);
+        let none = Escalation::default();
+        assert_eq!(mock.exec("pkg info nginx", &none, &mut collect), Ok(1));
+        assert_eq!(mock.exec("hostname", &none, &mut collect), Ok(0));
+        assert_eq!(mock.exec("true", &none, &mut collect), Ok(0));
+        assert_eq!(stdout, "gopher\n");
+        assert_eq!(mock.commands(), vec!["pkg info nginx", "hostname", "true"]);
+    }
+
+    #[test]
+    fn clones_share_files() {
+        let mock = MockTransport::new();
+        let mut clone = mock.clone();
+        clone.put(Path::new("script"), b"echo", 0o700).unwrap();
+        assert_eq!(mock.file("script"), Some(b"echo".to_vec()));
+
+        clone.remove(Path::new("script")).unwrap();
+        assert_eq!(clone.stat(Path::new("script")), Ok(None));
+        assert_eq!(mock.operations().len(), 3);
+    }
+}
@@ -1,30 +1,372 @@
-use crate::inventory::{Group, Inventory, Target};
-use crate::ExecutableTask;
+use crate::escalation::shell_quote;
+use crate::inventory::{self, Target};
+use crate::Escalation;
 
+use log::*;
+use serde::Serialize;
+use std::fmt;
+use std::io::Read;
 use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Instant;
 
+pub mod docker;
+pub mod jail;
+pub mod local;
+pub mod mock;
 pub mod ssh;
 
+/**
+ * TransportErrors are returned for failures which affect a single target, such
+ * as being unable to reach it or losing the connection part way through a
+ * task. They should never abort the execution on other targets.
+ */
+#[derive(Clone, Debug, PartialEq)]
 pub enum TransportError {
     GeneralError(String),
+    /// The target could not be reached at all
+    ConnectionFailed(String),
+    /// The target was reached but would not let us in
+    AuthenticationFailed(String),
+    /// The target's host key is unknown or does not match the one on record
+    HostKeyVerificationFailed(String),
+    /// Copying a file to or from the target failed
+    TransferFailed(String),
+    /// The command could not be started, or its output could not be read
+    ExecutionFailed(String),
+    /// An operation was attempted before a connection was established
+    NotConnected,
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::GeneralError(msg) => write!(f, "{}", msg),
+            TransportError::ConnectionFailed(msg) => write!(f, "connection failed: {}", msg),
+            TransportError::AuthenticationFailed(msg) => {
+                write!(f, "authentication failed: {}", msg)
+            }
+            TransportError::HostKeyVerificationFailed(msg) => {
+                write!(f, "host key verification failed: {}", msg)
+            }
+            TransportError::TransferFailed(msg) => write!(f, "file transfer failed: {}", msg),
+            TransportError::ExecutionFailed(msg) => write!(f, "execution failed: {}", msg),
+            TransportError::NotConnected => write!(f, "not connected to the target"),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+/**
+ * Which of the remote command's output streams some output came from
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/**
+ * What a transport knows about a file on the target
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileStat {
+    pub size: u64,
+    pub mode: i32,
+    pub is_dir: bool,
 }
 
 /**
  * The Transport trait allows for multiple transports to be implemented for
- * connecting to targets
+ * connecting to targets.
+ *
+ * Transports only provide the primitive operations on a target, everything
+ * else about running a task is up to the Runner.
  */
-pub trait Transport {
-    fn connect(&mut self, target: &Target) -> bool;
+pub trait Transport: Send {
+    fn connect(&mut self, target: &Target) -> Result<(), TransportError>;
     fn disconnect(&mut self);
-    fn file_exists(&self, path: &Path) -> Result<bool, TransportError>;
-    fn run(&mut self, command: &ExecutableTask, target: &Target, dry_run: bool) -> i32;
-    fn run_script(&mut self, script: &str) -> i32;
-    fn run_group(
+    /**
+     * Keep an idle connection from being dropped by the target or anything in
+     * between, failing if it has been dropped already
+     */
+    fn keepalive(&mut self) -> Result<(), TransportError> {
+        Ok(())
+    }
+    /**
+     * Run the shell command on the target, as the become user if escalation
+     * is enabled, passing its output to `output` as it arrives and returning
+     * its exit status.
+     *
+     * Output is passed along in whatever chunks it is read in, which need not
+     * end on a line boundary. Both streams must be read at once, so that a
+     * command which fills up one of them doesn't stall while the other is
+     * being waited on.
+     */
+    fn exec(
+        &mut self,
+        command: &str,
+        escalation: &Escalation,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<i32, TransportError>;
+    /**
+     * Like exec, but stop waiting on the command once the deadline has passed
+     * and return None. The command may well carry on running on the target,
+     * putting an end to it is up to the caller.
+     *
+     * Transports which can't give up part of the way through wait for the
+     * command to finish regardless.
+     */
+    fn exec_until(
         &mut self,
-        cmd: &ExecutableTask,
-        group: &Group,
-        inv: &Inventory,
-        dry_run: bool,
-    ) -> i32;
-    fn send_bytes(&self, remote_path: &Path, bytes: &Vec<u8>, mode: i32) -> bool;
+        command: &str,
+        escalation: &Escalation,
+        _deadline: Instant,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Option<i32>, TransportError> {
+        self.exec(command, escalation, output).map(Some)
+    }
+    fn put(&mut self, remote_path: &Path, bytes: &[u8], mode: i32) -> Result<(), TransportError>;
+    fn get(&mut self, remote_path: &Path) -> Result<Vec<u8>, TransportError>;
+    /// Returns None when the path doesn't exist
+    fn stat(&mut self, path: &Path) -> Result<Option<FileStat>, TransportError>;
+    fn remove(&mut self, path: &Path) -> Result<(), TransportError>;
+
+    /**
+     * Create the directory with the given mode. Like the rest of the file
+     * operations below, this runs a shell command as the login user unless
+     * the transport has a better way of doing it
+     */
+    fn mkdir(&mut self, path: &Path, mode: i32) -> Result<(), TransportError> {
+        let command = format!(
+            "mkdir -m {:o} {}",
+            mode,
+            shell_quote(&path.to_string_lossy())
+        );
+        run_ok(self, &command)
+    }
+
+    fn chmod(&mut self, path: &Path, mode: i32) -> Result<(), TransportError> {
+        let command = format!("chmod {:o} {}", mode, shell_quote(&path.to_string_lossy()));
+        run_ok(self, &command)
+    }
+
+    /// Change the owner of the path, given as `user` or `user:group`
+    fn chown(&mut self, path: &Path, owner: &str) -> Result<(), TransportError> {
+        let command = format!(
+            "chown {} {}",
+            shell_quote(owner),
+            shell_quote(&path.to_string_lossy())
+        );
+        run_ok(self, &command)
+    }
+}
+
+/**
+ * Run the command as the login user, failing with whatever it wrote to stderr
+ * if it exits non-zero
+ */
+pub(crate) fn run_ok<T: Transport + ?Sized>(
+    transport: &mut T,
+    command: &str,
+) -> Result<(), TransportError> {
+    let mut stderr = String::new();
+    let status = transport.exec(command, &Escalation::default(), &mut |stream, text| {
+        if stream == Stream::Stderr {
+            stderr.push_str(text);
+        }
+    })?;
+    if status != 0 {
+        return Err(TransportError::ExecutionFailed(format!(
+            "`{}` exited {}: {}",
+            command,
+            status,
+            stderr.trim()
+        )));
+    }
+    Ok(())
+}
+
+/**
+ * Create the transport which the target is configured to be reached with
+ */
+pub fn for_target(target: &Target) -> Box<dyn Transport> {
+    new(&target.transport(), target)
+}
+
+/**
+ * Create a transport of the given kind for the target
+ */
+pub fn new(kind: &inventory::Transport, target: &Target) -> Box<dyn Transport> {
+    match kind {
+        inventory::Transport::Ssh => Box::new(ssh::Ssh::default()),
+        inventory::Transport::Local => Box::new(local::Local::default()),
+        inventory::Transport::Docker => Box::new(docker::Docker::default()),
+        inventory::Transport::Jail | inventory::Transport::Chroot => {
+            Box::new(jail::Jail::new(new(&target.via(), target)))
+        }
+    }
+}
+
+/**
+ * Turns the bytes of an output stream into text as they arrive, holding back
+ * a multi-byte character which was split between two reads until the rest of
+ * it shows up
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Decoder {
+    pending: Vec<u8>,
+}
+
+impl Decoder {
+    pub(crate) fn decode(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let complete = match std::str::from_utf8(&self.pending) {
+            Ok(_) => self.pending.len(),
+            // Only an incomplete character at the very end is worth waiting
+            // on, anything else invalid is replaced right away
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => self.pending.len(),
+        };
+        let rest = self.pending.split_off(complete);
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending = rest;
+        text
+    }
+
+    /**
+     * Whatever is left once the stream has closed
+     */
+    pub(crate) fn finish(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
+
+/**
+ * Pass along what the child process writes to its piped stdout and stderr as
+ * it arrives, then wait for it to exit.
+ *
+ * Each stream is read on a thread of its own so that neither can fill up and
+ * stall the process while the other is being read.
+ *
+ * If the deadline passes first the child is killed, along with the rest of its
+ * process group when it leads one, and None is returned. The readers are left
+ * to finish on their own, since anything the child started may still be
+ * holding on to its pipes.
+ */
+pub(crate) fn stream_child(
+    child: &mut Child,
+    deadline: Option<Instant>,
+    output: &mut dyn FnMut(Stream, &str),
+) -> Result<Option<ExitStatus>, TransportError> {
+    let (sender, receiver) = mpsc::channel();
+    let pipes: [(Stream, Option<Box<dyn Read + Send>>); 2] = [
+        (
+            Stream::Stdout,
+            child
+                .stdout
+                .take()
+                .map(|p| Box::new(p) as Box<dyn Read + Send>),
+        ),
+        (
+            Stream::Stderr,
+            child
+                .stderr
+                .take()
+                .map(|p| Box::new(p) as Box<dyn Read + Send>),
+        ),
+    ];
+    for (stream, pipe) in pipes {
+        let (sender, mut pipe) = match pipe {
+            Some(pipe) => (sender.clone(), pipe),
+            None => continue,
+        };
+        thread::spawn(move || {
+            let mut buf = [0; 4096];
+            loop {
+                match pipe.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(count) => {
+                        if sender.send((stream, buf[..count].to_vec())).is_err() {
+                            // Nobody is listening any more
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        warn!("Failed to read the output of the command: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    let (mut out, mut err) = (Decoder::default(), Decoder::default());
+    let mut timed_out = false;
+    loop {
+        let received = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                receiver.recv_timeout(remaining)
+            }
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let (stream, bytes) = match received {
+            Ok(received) => received,
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                timed_out = true;
+                break;
+            }
+        };
+        let decoder = match stream {
+            Stream::Stdout => &mut out,
+            Stream::Stderr => &mut err,
+        };
+        output(stream, &decoder.decode(&bytes));
+    }
+    output(Stream::Stdout, &out.finish());
+    output(Stream::Stderr, &err.finish());
+
+    if timed_out {
+        debug!("The command ran out of time, killing it");
+        let group = Command::new("kill")
+            .args(["-KILL", "--", &format!("-{}", child.id())])
+            .stderr(Stdio::null())
+            .status();
+        if !matches!(group, Ok(status) if status.success()) {
+            if let Err(e) = child.kill() {
+                warn!("Failed to kill the command: {}", e);
+            }
+        }
+    }
+    let status = child
+        .wait()
+        .map_err(|e| TransportError::ExecutionFailed(format!("{}", e)))?;
+    Ok(if timed_out { None } else { Some(status) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_split_characters() {
+        let mut decoder = Decoder::default();
+        let bytes = "zap ⚡".as_bytes();
+        let split = bytes.len() - 1;
+        assert_eq!(decoder.decode(&bytes[..split]), "zap ");
+        assert_eq!(decoder.decode(&bytes[split..]), "⚡");
+        assert_eq!(decoder.decode(b"\xffok"), "\u{fffd}ok");
+        assert_eq!(decoder.decode(&bytes[4..5]), "");
+        assert_eq!(decoder.finish(), "\u{fffd}");
+    }
 }
@@ -0,0 +1,294 @@
+use log::*;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/**
+ * ClientConfig is the parsed form of an OpenSSH client configuration file,
+ * typically ~/.ssh/config
+ *
+ * Only the handful of keywords which zap makes use of are interpreted, the
+ * rest are ignored. `Match` blocks are not supported and are skipped entirely.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct ClientConfig {
+    blocks: Vec<HostBlock>,
+}
+
+/**
+ * A `Host` block, the patterns it applies to and the options set within it
+ */
+#[derive(Clone, Debug)]
+struct HostBlock {
+    patterns: Vec<String>,
+    options: Vec<(String, String)>,
+}
+
+/**
+ * HostSettings are the options from the ssh config which apply to a single host
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HostSettings {
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<PathBuf>,
+    pub proxy_jump: Option<String>,
+}
+
+impl ClientConfig {
+    /**
+     * Load the user's ~/.ssh/config, any failure to do so is treated the same
+     * as the file not existing, as it is with ssh itself
+     */
+    pub fn load() -> Self {
+        match std::env::var("HOME") {
+            Ok(home) => Self::from_path(&Path::new(&home).join(".ssh").join("config")),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Self {
+        let mut contents = String::new();
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+            Ok(_) => contents.parse().unwrap_or_default(),
+            Err(err) => {
+                debug!("Not using ssh config {}: {}", path.display(), err);
+                Self::default()
+            }
+        }
+    }
+
+    /**
+     * Collect the settings for the given host alias.
+     *
+     * Like ssh, the first value found for each option wins, except for
+     * IdentityFile which may be given multiple times
+     */
+    pub fn lookup(&self, alias: &str) -> HostSettings {
+        let mut settings = HostSettings::default();
+
+        for block in self.blocks.iter().filter(|b| b.matches(alias)) {
+            for (key, value) in block.options.iter() {
+                match key.as_str() {
+                    "hostname" if settings.hostname.is_none() => {
+                        settings.hostname = Some(value.replace("%h", alias));
+                    }
+                    "user" if settings.user.is_none() => {
+                        settings.user = Some(value.clone());
+                    }
+                    "port" if settings.port.is_none() => match value.parse() {
+                        Ok(port) => settings.port = Some(port),
+                        Err(_) => warn!("Ignoring invalid Port `{}` in ssh config", value),
+                    },
+                    "identityfile" => {
+                        settings.identity_files.push(expand_path(value, alias));
+                    }
+                    "proxyjump" if settings.proxy_jump.is_none() => {
+                        settings.proxy_jump = Some(value.clone());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        settings
+    }
+}
+
+impl FromStr for ClientConfig {
+    type Err = std::convert::Infallible;
+
+    fn from_str(buf: &str) -> Result<Self, Self::Err> {
+        // Options before the first Host block apply to every host
+        let mut current = Some(HostBlock {
+            patterns: vec!["*".into()],
+            options: vec![],
+        });
+        let mut blocks = vec![];
+
+        for line in buf.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let split = line
+                .find(|c: char| c.is_whitespace() || c == '=')
+                .unwrap_or(line.len());
+            let key = line[..split].to_lowercase();
+            let value = line[split..].trim_start();
+            let value = value.strip_prefix('=').unwrap_or(value).trim();
+            let value = value.trim_matches('"').to_string();
+
+            match key.as_str() {
+                "host" => {
+                    blocks.extend(current.take());
+                    current = Some(HostBlock {
+                        patterns: value.split_whitespace().map(String::from).collect(),
+                        options: vec![],
+                    });
+                }
+                "match" => {
+                    debug!("Skipping unsupported Match block in ssh config: {}", line);
+                    blocks.extend(current.take());
+                }
+                _ => {
+                    if let Some(block) = current.as_mut() {
+                        block.options.push((key, value));
+                    }
+                }
+            }
+        }
+        blocks.extend(current.take());
+        Ok(Self { blocks })
+    }
+}
+
+impl HostBlock {
+    /**
+     * A block applies when any of its patterns match the alias, and none of
+     * its negated (`!pattern`) patterns do
+     */
+    fn matches(&self, alias: &str) -> bool {
+        let mut matched = false;
+        for pattern in self.patterns.iter() {
+            if let Some(negated) = pattern.strip_prefix('!') {
+                if glob_match(negated, alias) {
+                    return false;
+                }
+            } else if glob_match(pattern, alias) {
+                matched = true;
+            }
+        }
+        matched
+    }
+}
+
+/**
+ * Match ssh_config(5) style patterns, where `*` matches zero or more
+ * characters and `?` matches exactly one
+ */
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match (pattern.first(), text.first()) {
+            (None, None) => true,
+            (Some('*'), _) => {
+                matches(&pattern[1..], text) || (!text.is_empty() && matches(pattern, &text[1..]))
+            }
+            (Some('?'), Some(_)) => matches(&pattern[1..], &text[1..]),
+            (Some(p), Some(t)) if p.eq_ignore_ascii_case(t) => matches(&pattern[1..], &text[1..]),
+            _ => false,
+        }
+    }
+    matches(&pattern, &text)
+}
+
+/**
+ * Expand the leading `~` and the `%d` (home directory) and `%h` (host) tokens
+ * which are commonly used in IdentityFile paths
+ */
+fn expand_path(value: &str, alias: &str) -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_default();
+    let value = match value.strip_prefix("~/") {
+        Some(rest) => format!("{}/{}", home, rest),
+        None => value.to_string(),
+    };
+    PathBuf::from(
+        value
+            .replace("%d", &home)
+            .replace("%h", alias)
+            .replace("%%", "%"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+# Defaults for everything
+User fallback
+
+Host prod-db-*
+    HostName %h.internal.example.com
+    User dba
+    Port 2222
+    IdentityFile /keys/db
+
+Host bastion
+    HostName=203.0.113.10
+    User "jumper"
+
+Host *.example.com !secret.example.com
+    Port 2200
+    ProxyJump bastion
+
+Match host foo
+    User ignored
+
+Host *
+    IdentityFile /keys/default
+    Port 22
+"#;
+
+    #[test]
+    fn lookup_alias() {
+        let config: ClientConfig = CONFIG.parse().unwrap();
+        let settings = config.lookup("prod-db-1");
+        assert_eq!(
+            settings.hostname,
+            Some("prod-db-1.internal.example.com".into())
+        );
+        // The global User comes first, so it wins just as it does for ssh
+        assert_eq!(settings.user, Some("fallback".into()));
+        assert_eq!(settings.port, Some(2222));
+        assert_eq!(
+            settings.identity_files,
+            vec![PathBuf::from("/keys/db"), PathBuf::from("/keys/default")]
+        );
+        assert_eq!(settings.proxy_jump, None);
+    }
+
+    #[test]
+    fn lookup_with_equals_and_quotes() {
+        let config: ClientConfig = "Host bastion\n  HostName=203.0.113.10\n  User \"jumper\""
+            .parse()
+            .unwrap();
+        let settings = config.lookup("bastion");
+        assert_eq!(settings.hostname, Some("203.0.113.10".into()));
+        assert_eq!(settings.user, Some("jumper".into()));
+    }
+
+    #[test]
+    fn lookup_negated_pattern() {
+        let config: ClientConfig = CONFIG.parse().unwrap();
+        assert_eq!(
+            config.lookup("web.example.com").proxy_jump,
+            Some("bastion".into())
+        );
+        assert_eq!(config.lookup("web.example.com").port, Some(2200));
+        assert_eq!(config.lookup("secret.example.com").proxy_jump, None);
+        assert_eq!(config.lookup("secret.example.com").port, Some(22));
+    }
+
+    #[test]
+    fn lookup_unknown_host() {
+        let config: ClientConfig = CONFIG.parse().unwrap();
+        let settings = config.lookup("192.168.1.1");
+        assert_eq!(settings.hostname, None);
+        assert_eq!(settings.port, Some(22));
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("prod-?", "prod-1"));
+        assert!(!glob_match("prod-?", "prod-10"));
+        assert!(glob_match("*.EXAMPLE.com", "www.example.com"));
+        assert!(!glob_match("*.example.com", "example.org"));
+    }
+}
@@ -0,0 +1,345 @@
+use crate::inventory::HostKeyPolicy;
+use crate::TransportError;
+
+use log::*;
+use ssh2::{CheckResult, HostKeyType, KnownHostFileKind, KnownHosts, Session};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/**
+ * The result of checking a host key against the known_hosts file
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostKeyStatus {
+    /// The key was already present in the known_hosts file
+    Known,
+    /// The host was not present and its key has now been recorded
+    Added,
+}
+
+/**
+ * Return the known_hosts file to use, either the one configured or the
+ * user's ~/.ssh/known_hosts
+ */
+pub fn path(configured: Option<&String>) -> Result<PathBuf, TransportError> {
+    if let Some(configured) = configured {
+        return Ok(PathBuf::from(configured));
+    }
+    std::env::var("HOME")
+        .map(|home| Path::new(&home).join(".ssh").join("known_hosts"))
+        .map_err(|_| {
+            TransportError::HostKeyVerificationFailed(
+                "$HOME is not set and no known_hosts file was configured".into(),
+            )
+        })
+}
+
+/**
+ * Verify the handshaken session's host key according to the policy, recording
+ * it in the known_hosts file if the policy allows for new hosts
+ */
+pub fn verify(
+    session: &Session,
+    host: &str,
+    port: u16,
+    policy: &HostKeyPolicy,
+    file: &Path,
+) -> Result<(), TransportError> {
+    if *policy == HostKeyPolicy::Off {
+        debug!("Host key verification is disabled for {}", host);
+        return Ok(());
+    }
+    verify_key(session, host_key(session, host)?, host, port, policy, file)
+}
+
+/**
+ * Verify the host key according to the policy, like verify() but with the
+ * key given rather than taken from the session
+ */
+fn verify_key(
+    session: &Session,
+    key: (&[u8], HostKeyType),
+    host: &str,
+    port: u16,
+    policy: &HostKeyPolicy,
+    file: &Path,
+) -> Result<(), TransportError> {
+    match policy {
+        HostKeyPolicy::Off => Ok(()),
+        HostKeyPolicy::Strict => match check(session, key.0, host, port, file)? {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound => Err(TransportError::HostKeyVerificationFailed(format!(
+                "{} is not in {}, run `zap keyscan` to record its key",
+                host,
+                file.display()
+            ))),
+            result => Err(failure(result, host, file)),
+        },
+        HostKeyPolicy::AcceptNew => record_key(session, key, host, port, file).map(|_| ()),
+    }
+}
+
+/**
+ * Record the session's host key in the known_hosts file unless it is already
+ * there. A key which differs from the one on record is always an error.
+ */
+pub fn record(
+    session: &Session,
+    host: &str,
+    port: u16,
+    file: &Path,
+) -> Result<HostKeyStatus, TransportError> {
+    record_key(session, host_key(session, host)?, host, port, file)
+}
+
+fn record_key(
+    session: &Session,
+    key: (&[u8], HostKeyType),
+    host: &str,
+    port: u16,
+    file: &Path,
+) -> Result<HostKeyStatus, TransportError> {
+    match check(session, key.0, host, port, file)? {
+        CheckResult::Match => Ok(HostKeyStatus::Known),
+        CheckResult::NotFound => {
+            append(session, key, host, port, file)?;
+            Ok(HostKeyStatus::Added)
+        }
+        result => Err(failure(result, host, file)),
+    }
+}
+
+fn failure(result: CheckResult, host: &str, file: &Path) -> TransportError {
+    match result {
+        CheckResult::Mismatch => TransportError::HostKeyVerificationFailed(format!(
+            "the host key for {} does not match the one in {}, someone could be \
+             doing something nasty!",
+            host,
+            file.display()
+        )),
+        _ => TransportError::HostKeyVerificationFailed(format!(
+            "unable to check the host key for {} against {}",
+            host,
+            file.display()
+        )),
+    }
+}
+
+/**
+ * The name known_hosts uses for a host, which includes the port whenever it
+ * isn't the default
+ */
+fn entry_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+fn known_hosts(session: &Session) -> Result<KnownHosts, TransportError> {
+    session.known_hosts().map_err(|e| {
+        TransportError::HostKeyVerificationFailed(format!("failed to load known hosts: {}", e))
+    })
+}
+
+/**
+ * The host key the server offered during the handshake
+ */
+fn host_key<'a>(
+    session: &'a Session,
+    host: &str,
+) -> Result<(&'a [u8], HostKeyType), TransportError> {
+    session.host_key().ok_or_else(|| {
+        TransportError::HostKeyVerificationFailed(format!("{} did not offer a host key", host))
+    })
+}
+
+fn check(
+    session: &Session,
+    key: &[u8],
+    host: &str,
+    port: u16,
+    file: &Path,
+) -> Result<CheckResult, TransportError> {
+    let mut hosts = known_hosts(session)?;
+    if file.exists() {
+        hosts
+            .read_file(file, KnownHostFileKind::OpenSSH)
+            .map_err(|e| {
+                TransportError::HostKeyVerificationFailed(format!(
+                    "failed to read {}: {}",
+                    file.display(),
+                    e
+                ))
+            })?;
+    }
+    Ok(hosts.check_port(host, port, key))
+}
+
+/**
+ * Append the host key to the known_hosts file.
+ *
+ * This formats the single new entry with libssh2 and appends it rather than
+ * writing the whole collection back out, so the rest of the file is left
+ * exactly as the user had it.
+ */
+fn append(
+    session: &Session,
+    (key, key_type): (&[u8], HostKeyType),
+    host: &str,
+    port: u16,
+    file: &Path,
+) -> Result<(), TransportError> {
+    let write_failed = |e: String| {
+        TransportError::HostKeyVerificationFailed(format!(
+            "failed to record the host key for {} in {}: {}",
+            host,
+            file.display(),
+            e
+        ))
+    };
+    // libssh2 crashes adding an entry with an empty comment
+    let mut entry = known_hosts(session)?;
+    entry
+        .add(&entry_name(host, port), key, "zap", key_type.into())
+        .map_err(|e| write_failed(format!("{}", e)))?;
+    let hosts = entry.hosts().map_err(|e| write_failed(format!("{}", e)))?;
+    let line = match hosts.first() {
+        Some(added) => entry
+            .write_string(added, KnownHostFileKind::OpenSSH)
+            .map_err(|e| write_failed(format!("{}", e)))?,
+        None => return Err(write_failed("no entry was generated".into())),
+    };
+
+    if let Some(parent) = file.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| write_failed(format!("{}", e)))?;
+    }
+    let mut known_hosts = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)
+        .map_err(|e| write_failed(format!("{}", e)))?;
+    writeln!(known_hosts, "{}", line.trim_end()).map_err(|e| write_failed(format!("{}", e)))?;
+
+    info!("Added the host key for {} to {}", host, file.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_name_default_port() {
+        assert_eq!(entry_name("gopher", 22), "gopher");
+    }
+
+    #[test]
+    fn entry_name_other_port() {
+        assert_eq!(entry_name("gopher", 2222), "[gopher]:2222");
+    }
+
+    /**
+     * An ed25519 public key blob, filled with the given byte
+     */
+    fn ed25519(fill: u8) -> Vec<u8> {
+        let mut blob = b"\0\0\0\x0bssh-ed25519\0\0\0\x20".to_vec();
+        blob.extend_from_slice(&[fill; 32]);
+        blob
+    }
+
+    /**
+     * A known_hosts file which doesn't exist yet, in a fresh directory
+     */
+    fn known_hosts_file(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zap-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.join("known_hosts")
+    }
+
+    #[test]
+    fn strict_rejects_unknown_host() {
+        let session = Session::new().unwrap();
+        let file = known_hosts_file("strict-unknown");
+        let key = ed25519(1);
+        let verified = verify_key(
+            &session,
+            (&key, HostKeyType::Ed255219),
+            "gopher",
+            22,
+            &HostKeyPolicy::Strict,
+            &file,
+        );
+        assert!(matches!(
+            verified,
+            Err(TransportError::HostKeyVerificationFailed(_))
+        ));
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn accept_new_records_once() {
+        let session = Session::new().unwrap();
+        let file = known_hosts_file("accept-new");
+        let key = ed25519(1);
+        for _ in 0..2 {
+            verify_key(
+                &session,
+                (&key, HostKeyType::Ed255219),
+                "gopher",
+                2222,
+                &HostKeyPolicy::AcceptNew,
+                &file,
+            )
+            .expect("Failed to verify");
+        }
+        let recorded = std::fs::read_to_string(&file).unwrap();
+        assert_eq!(recorded.lines().count(), 1);
+        assert!(recorded.starts_with("[gopher]:2222 ssh-ed25519 "));
+
+        // Once recorded, the key is known under a strict policy too
+        verify_key(
+            &session,
+            (&key, HostKeyType::Ed255219),
+            "gopher",
+            2222,
+            &HostKeyPolicy::Strict,
+            &file,
+        )
+        .expect("Failed to verify");
+        std::fs::remove_dir_all(file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn mismatched_key_rejected() {
+        let session = Session::new().unwrap();
+        let file = known_hosts_file("mismatch");
+        let key = ed25519(1);
+        assert_eq!(
+            record_key(&session, (&key, HostKeyType::Ed255219), "gopher", 22, &file),
+            Ok(HostKeyStatus::Added)
+        );
+
+        let other = ed25519(2);
+        for policy in &[HostKeyPolicy::Strict, HostKeyPolicy::AcceptNew] {
+            let verified = verify_key(
+                &session,
+                (&other, HostKeyType::Ed255219),
+                "gopher",
+                22,
+                policy,
+                &file,
+            );
+            match verified {
+                Err(TransportError::HostKeyVerificationFailed(message)) => {
+                    assert!(message.contains("does not match"))
+                }
+                other => panic!("Expected a mismatch, got {:?}", other),
+            }
+        }
+        assert_eq!(std::fs::read_to_string(&file).unwrap().lines().count(), 1);
+        std::fs::remove_dir_all(file.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,1282 @@
+use crate::escalation::{shell_quote, PasswordPrompt, Prompted};
+use crate::inventory::{HostKeyPolicy, ProxyJump, SshConfig, Target, TargetUri};
+use crate::transport::{self, Decoder, FileStat, Stream, Transport};
+use crate::{Escalation, TransportError};
+
+use log::*;
+use ssh2::{Channel, ErrorCode, OpenFlags, OpenType, Session, Sftp};
+use std::convert::TryInto;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub mod config;
+pub mod known_hosts;
+mod tunnel;
+
+pub use known_hosts::HostKeyStatus;
+
+use config::{ClientConfig, HostSettings};
+
+const DEFAULT_PORT: u16 = 22;
+/// How long to wait before checking on a quiet command's output again
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How many seconds a session may sit idle before a keepalive is due
+const KEEPALIVE_INTERVAL: u32 = 30;
+/// The SFTP status codes for a path which doesn't exist
+const SFTP_NO_SUCH_FILE: i32 = 2;
+const SFTP_NO_SUCH_PATH: i32 = 10;
+
+/**
+ * Ssh runs commands over an SSH session, and handles files over the SFTP
+ * subsystem. Targets which have SFTP disabled get by with scp and the shell.
+ */
+#[derive(Clone, Default)]
+pub struct Ssh {
+    session: Option<Session>,
+    /// Sessions to the jump hosts which the session is tunnelled through
+    jumps: Vec<Session>,
+    /// The SFTP subsystem, opened the first time a file is handled
+    sftp: Option<Arc<Sftp>>,
+    /// The target refused to start the SFTP subsystem
+    sftp_unavailable: bool,
+}
+
+/**
+ * Endpoint is where, as whom and with which credentials a target should be
+ * connected to, once the target's uri, its inventory ssh config and the
+ * user's ~/.ssh/config have all been taken into account
+ */
+struct Endpoint {
+    user: String,
+    host: String,
+    port: u16,
+    password: Option<String>,
+    privatekey_path: Option<PathBuf>,
+    /// IdentityFiles from ~/.ssh/config, tried when the agent fails
+    identity_files: Vec<PathBuf>,
+    host_key_policy: HostKeyPolicy,
+    known_hosts: Option<String>,
+    /// Jump hosts to tunnel through to reach this endpoint, in order
+    jumps: Vec<Endpoint>,
+    connect_timeout: Option<Duration>,
+}
+
+impl Endpoint {
+    fn from_target(target: &Target) -> Result<Self, TransportError> {
+        let uri = target
+            .parse_uri()
+            .map_err(|e| TransportError::GeneralError(format!("{}", e)))?;
+        if uri.scheme != "ssh" {
+            return Err(TransportError::GeneralError(format!(
+                "the ssh transport cannot connect to a `{}` uri",
+                uri.scheme
+            )));
+        }
+        let sshconfig = target.config.as_ref().and_then(|c| c.ssh.as_ref());
+        Self::resolve(&uri, sshconfig, &ClientConfig::load())
+    }
+
+    /**
+     * Settle on the values to connect with. The most specific source wins:
+     * the uri, then the inventory's ssh config, then ~/.ssh/config
+     *
+     * Jump hosts are resolved the same way, with their own credentials from
+     * the proxy_jump config, but the target's host key settings
+     */
+    fn resolve(
+        uri: &TargetUri,
+        sshconfig: Option<&SshConfig>,
+        client: &ClientConfig,
+    ) -> Result<Self, TransportError> {
+        let settings = client.lookup(&uri.host);
+        let proxy_jump = sshconfig
+            .and_then(|s| s.proxy_jump.clone())
+            .or_else(|| settings.proxy_jump.clone().map(ProxyJump::Chain))
+            // `none` allows a target to opt out of an inherited proxy_jump
+            .filter(|jump| *jump != ProxyJump::Chain("none".into()));
+
+        let mut endpoint = Self::resolve_hop(uri, sshconfig, settings)?;
+
+        if let Some(proxy_jump) = proxy_jump {
+            for hop in proxy_jump.hops() {
+                let hop_uri: TargetUri = hop.uri.parse().map_err(|e| {
+                    TransportError::GeneralError(format!("invalid proxy_jump: {}", e))
+                })?;
+                let hop_config = SshConfig {
+                    password: hop.password,
+                    privatekey_path: hop.privatekey_path,
+                    host_key_policy: sshconfig.and_then(|s| s.host_key_policy.clone()),
+                    known_hosts: sshconfig.and_then(|s| s.known_hosts.clone()),
+                    connect_timeout: sshconfig.and_then(|s| s.connect_timeout),
+                    ..Default::default()
+                };
+                let settings = client.lookup(&hop_uri.host);
+                endpoint
+                    .jumps
+                    .push(Self::resolve_hop(&hop_uri, Some(&hop_config), settings)?);
+            }
+        }
+        Ok(endpoint)
+    }
+
+    fn resolve_hop(
+        uri: &TargetUri,
+        sshconfig: Option<&SshConfig>,
+        settings: HostSettings,
+    ) -> Result<Self, TransportError> {
+        let port = uri
+            .port
+            .or_else(|| sshconfig.and_then(|s| s.port))
+            .or(settings.port)
+            .unwrap_or(DEFAULT_PORT);
+        let user = uri
+            .user
+            .clone()
+            .or_else(|| sshconfig.and_then(|s| s.user.clone()))
+            .or(settings.user);
+        let user = match user {
+            Some(user) => user,
+            None => std::env::var("USER").map_err(|_| {
+                TransportError::AuthenticationFailed(
+                    "no ssh user was configured and $USER is not set".into(),
+                )
+            })?,
+        };
+
+        Ok(Self {
+            user,
+            host: settings.hostname.unwrap_or_else(|| uri.host.clone()),
+            port,
+            password: sshconfig.and_then(|s| s.password.clone()),
+            privatekey_path: sshconfig.and_then(|s| s.privatekey_path.as_ref().map(PathBuf::from)),
+            identity_files: settings.identity_files,
+            host_key_policy: sshconfig
+                .and_then(|s| s.host_key_policy.clone())
+                .unwrap_or_default(),
+            known_hosts: sshconfig.and_then(|s| s.known_hosts.clone()),
+            jumps: vec![],
+            connect_timeout: sshconfig
+                .and_then(|s| s.connect_timeout)
+                .map(Duration::from_secs),
+        })
+    }
+
+    fn known_hosts_path(&self) -> Result<PathBuf, TransportError> {
+        known_hosts::path(self.known_hosts.as_ref())
+    }
+
+    /**
+     * Open a new session to the endpoint, tunnelling through each of its jump
+     * hosts in turn, and stopping after the final handshake so the host key
+     * can be checked before any credentials are sent.
+     *
+     * The jump host sessions are returned alongside, they must be kept around
+     * for as long as the session is in use.
+     */
+    fn open(&self) -> Result<(Session, Vec<Session>), TransportError> {
+        let mut jumps: Vec<Session> = vec![];
+
+        for hop in self.jumps.iter() {
+            let session = hop.handshake(jumps.last())?;
+            hop.verify(&session)?;
+            hop.authenticate(&session)?;
+            // The connect timeout still covers opening the tunnel through the
+            // jump host, which clears it once the tunnel is open
+            jumps.push(session);
+        }
+
+        let session = self.handshake(jumps.last())?;
+        Ok((session, jumps))
+    }
+
+    /**
+     * Handshake with the endpoint, either directly or through the given jump
+     * host session
+     */
+    fn handshake(&self, via: Option<&Session>) -> Result<Session, TransportError> {
+        debug!(
+            "Connecting to {}@{} on port {}",
+            self.user, self.host, self.port
+        );
+        let tcp = match via {
+            Some(jump) => tunnel::open(jump, &self.host, self.port)
+                .map_err(TransportError::ConnectionFailed)?,
+            None => self.connect_tcp().map_err(|e| {
+                TransportError::ConnectionFailed(format!(
+                    "could not reach {} on port {}: {}",
+                    self.host, self.port, e
+                ))
+            })?,
+        };
+        let mut session = Session::new().map_err(|e| {
+            TransportError::GeneralError(format!("failed to create an SSH session: {}", e))
+        })?;
+        if let Some(timeout) = self.connect_timeout {
+            // Covers authenticating too, until the caller resets it
+            session.set_timeout(timeout.as_millis().try_into().unwrap_or(u32::MAX));
+        }
+        session.set_tcp_stream(tcp);
+        session.handshake().map_err(|e| {
+            TransportError::ConnectionFailed(format!(
+                "SSH handshake with {} failed: {}",
+                self.host, e
+            ))
+        })?;
+        Ok(session)
+    }
+
+    /**
+     * Connect to the first of the host's addresses which will have us, taking
+     * no longer than the connect_timeout for each
+     */
+    fn connect_tcp(&self) -> std::io::Result<TcpStream> {
+        let timeout = match self.connect_timeout {
+            Some(timeout) => timeout,
+            None => return TcpStream::connect((self.host.as_str(), self.port)),
+        };
+        let mut last_error = None;
+        for address in (self.host.as_str(), self.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, timeout) {
+                Ok(tcp) => return Ok(tcp),
+                Err(err) => last_error = Some(err),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            std::io::Error::new(ErrorKind::NotFound, "the host has no addresses")
+        }))
+    }
+
+    fn verify(&self, session: &Session) -> Result<(), TransportError> {
+        if self.host_key_policy == HostKeyPolicy::Off {
+            return Ok(());
+        }
+        known_hosts::verify(
+            session,
+            &self.host,
+            self.port,
+            &self.host_key_policy,
+            &self.known_hosts_path()?,
+        )
+    }
+
+    /**
+     * Authenticate with the password or private key from the inventory if
+     * either was given, otherwise with the agent and then any IdentityFiles
+     * from ~/.ssh/config
+     */
+    fn authenticate(&self, session: &Session) -> Result<(), TransportError> {
+        let user = &self.user;
+
+        // requires PasswordAuthentication yes
+        if let Some(password) = &self.password {
+            return session.userauth_password(user, password).map_err(|e| {
+                TransportError::AuthenticationFailed(format!(
+                    "password authentication as {} was rejected: {}",
+                    user, e
+                ))
+            });
+        }
+
+        if let Some(privatekey_path) = &self.privatekey_path {
+            return session
+                .userauth_pubkey_file(user, None, privatekey_path, None)
+                .map_err(|e| {
+                    TransportError::AuthenticationFailed(format!(
+                        "public key authentication as {} with {} was rejected: {}",
+                        user,
+                        privatekey_path.display(),
+                        e
+                    ))
+                });
+        }
+
+        let mut failures = vec![];
+        match session.userauth_agent(user) {
+            Ok(_) => return Ok(()),
+            Err(e) => failures.push(format!("agent: {}", e)),
+        }
+
+        for identity in self.identity_files.iter().filter(|p| p.exists()) {
+            match session.userauth_pubkey_file(user, None, identity, None) {
+                Ok(_) => return Ok(()),
+                Err(e) => failures.push(format!("{}: {}", identity.display(), e)),
+            }
+        }
+
+        Err(TransportError::AuthenticationFailed(format!(
+            "no method succeeded for {} ({})",
+            user,
+            failures.join(", ")
+        )))
+    }
+}
+
+impl Ssh {
+    /**
+     * Record the target's host key in the known_hosts file, without
+     * authenticating or running anything on the target.
+     *
+     * A host key which differs from the one already on record is an error
+     */
+    pub fn keyscan(target: &Target) -> Result<HostKeyStatus, TransportError> {
+        let endpoint = Endpoint::from_target(target)?;
+        let (session, _jumps) = endpoint.open()?;
+        known_hosts::record(
+            &session,
+            &endpoint.host,
+            endpoint.port,
+            &endpoint.known_hosts_path()?,
+        )
+    }
+
+    /**
+     * Return the currently connected session, or an error if connect() has
+     * not yet succeeded
+     */
+    fn session(&self) -> Result<&Session, TransportError> {
+        self.session.as_ref().ok_or(TransportError::NotConnected)
+    }
+
+    /**
+     * Return the SFTP subsystem, starting it if need be, or None if the
+     * target doesn't offer it
+     */
+    fn sftp(&mut self) -> Result<Option<Arc<Sftp>>, TransportError> {
+        if self.sftp.is_none() && !self.sftp_unavailable {
+            match self.session()?.sftp() {
+                Ok(sftp) => self.sftp = Some(Arc::new(sftp)),
+                Err(err) => {
+                    debug!("SFTP is unavailable, falling back to scp: {}", err);
+                    self.sftp_unavailable = true;
+                }
+            }
+        }
+        Ok(self.sftp.clone())
+    }
+
+    fn scp_put(&self, remote_path: &Path, bytes: &[u8], mode: i32) -> Result<(), TransportError> {
+        let transfer_failed = |e: ssh2::Error| {
+            TransportError::TransferFailed(format!("{}: {}", remote_path.display(), e))
+        };
+        let size = bytes.len().try_into().map_err(|_| {
+            TransportError::TransferFailed(format!(
+                "{} is too large to send",
+                remote_path.display()
+            ))
+        })?;
+
+        let mut remote_file = self
+            .session()?
+            .scp_send(remote_path, mode, size, None)
+            .map_err(transfer_failed)?;
+        remote_file.write_all(bytes).map_err(|e| {
+            TransportError::TransferFailed(format!("{}: {}", remote_path.display(), e))
+        })?;
+        // Close the channel and wait for the whole content to be tranferred
+        remote_file.send_eof().map_err(transfer_failed)?;
+        remote_file.wait_eof().map_err(transfer_failed)?;
+        remote_file.close().map_err(transfer_failed)?;
+        remote_file.wait_close().map_err(transfer_failed)?;
+        Ok(())
+    }
+
+    fn scp_get(&self, remote_path: &Path) -> Result<Vec<u8>, TransportError> {
+        let transfer_failed = |e: ssh2::Error| {
+            TransportError::TransferFailed(format!("{}: {}", remote_path.display(), e))
+        };
+        let (mut remote_file, _) = self
+            .session()?
+            .scp_recv(remote_path)
+            .map_err(transfer_failed)?;
+        let mut bytes = vec![];
+        remote_file.read_to_end(&mut bytes).map_err(|e| {
+            TransportError::TransferFailed(format!("{}: {}", remote_path.display(), e))
+        })?;
+        remote_file.send_eof().map_err(transfer_failed)?;
+        remote_file.wait_eof().map_err(transfer_failed)?;
+        remote_file.close().map_err(transfer_failed)?;
+        remote_file.wait_close().map_err(transfer_failed)?;
+        Ok(bytes)
+    }
+
+    /**
+     * Without SFTP the only way to look at a path is to ask `ls`, which is
+     * the same on every target, unlike `stat`
+     */
+    fn shell_stat(&mut self, path: &Path) -> Result<Option<FileStat>, TransportError> {
+        let quoted = shell_quote(&path.to_string_lossy());
+        let command = format!("if [ -e {} ]; then ls -ldn {}; fi", quoted, quoted);
+        let mut listing = String::new();
+        let status = self.exec(&command, &Escalation::default(), &mut |stream, text| {
+            if stream == Stream::Stdout {
+                listing.push_str(text);
+            }
+        })?;
+        if status != 0 {
+            return Err(TransportError::GeneralError(format!(
+                "failed to check {}: `ls` exited {}",
+                path.display(),
+                status
+            )));
+        }
+        if listing.trim().is_empty() {
+            return Ok(None);
+        }
+        parse_listing(&listing).map(Some).ok_or_else(|| {
+            TransportError::GeneralError(format!(
+                "failed to check {}: could not understand `{}`",
+                path.display(),
+                listing.trim()
+            ))
+        })
+    }
+
+    /**
+     * Change the attributes of the path over SFTP, or with the given shell
+     * command when SFTP is unavailable
+     */
+    fn setstat(
+        &mut self,
+        path: &Path,
+        stat: ssh2::FileStat,
+        command: &str,
+    ) -> Result<(), TransportError> {
+        match self.sftp()? {
+            Some(sftp) => sftp.setstat(path, stat).map_err(|e| {
+                TransportError::GeneralError(format!(
+                    "failed to {} {}: {}",
+                    command,
+                    path.display(),
+                    e
+                ))
+            }),
+            None => {
+                let command = format!("{} {}", command, shell_quote(&path.to_string_lossy()));
+                transport::run_ok(self, &command)
+            }
+        }
+    }
+
+    /**
+     * Open a channel and execute the command on it, as the become user when
+     * escalation is enabled, providing the become password if one was given
+     * once it is asked for.
+     *
+     * Any output read while waiting on a password prompt is passed along,
+     * through the prompt which the rest of the output must go through too.
+     * None is returned if the deadline passed before the become method let
+     * the command run.
+     */
+    fn open_exec(
+        &self,
+        command: &str,
+        escalation: &Escalation,
+        prompt: Option<&mut PasswordPrompt>,
+        deadline: Option<Instant>,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Option<Channel>, TransportError> {
+        let exec_failed = |e: ssh2::Error| TransportError::ExecutionFailed(format!("{}", e));
+        let session = self.session()?;
+        let mut channel = session.channel_session().map_err(|e| {
+            TransportError::ExecutionFailed(format!("failed to open a channel: {}", e))
+        })?;
+
+        if escalation.prompts_on_tty() {
+            channel
+                .request_pty("dumb", None, None)
+                .map_err(exec_failed)?;
+        }
+        let wrapped = escalation.wrap(command);
+        trace!("Executing: {}", wrapped);
+        channel.exec(&wrapped).map_err(exec_failed)?;
+
+        let (password, prompt) = match escalation.password().zip(prompt) {
+            Some(prompted) => prompted,
+            None => return Ok(Some(channel)),
+        };
+        session.set_blocking(false);
+        let awaited = Self::await_prompt(&mut channel, prompt, deadline, output);
+        session.set_blocking(true);
+        match awaited? {
+            Awaited::Prompted => {
+                let sent = channel
+                    .write_all(format!("{}\n", password).as_bytes())
+                    .and_then(|_| channel.flush());
+                sent.map_err(|e| {
+                    TransportError::ExecutionFailed(format!("failed to send the password: {}", e))
+                })?;
+            }
+            Awaited::NotAsked => {}
+            Awaited::TimedOut => {
+                Self::abandon(&mut channel);
+                return Ok(None);
+            }
+        }
+        if !escalation.prompts_on_tty() {
+            // Nothing else is coming on stdin, so a rejected password fails
+            // the command rather than leaving sudo waiting, and a command
+            // which reads stdin gets nothing
+            channel.send_eof().map_err(exec_failed)?;
+        }
+        Ok(Some(channel))
+    }
+
+    /**
+     * Read from the channel until the become method prompts for the password,
+     * or it becomes clear that it isn't going to, passing along anything which
+     * was read that wasn't the prompt itself.
+     *
+     * The session must be in non-blocking mode, so that a command which
+     * neither prompts nor finishes can still run out of time.
+     */
+    fn await_prompt<C: ExecChannel>(
+        channel: &mut C,
+        prompt: &mut PasswordPrompt,
+        deadline: Option<Instant>,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Awaited, TransportError> {
+        let mut buf = [0; 1024];
+        loop {
+            let mut idle = true;
+            for stream in [Stream::Stdout, Stream::Stderr] {
+                match channel.read_stream(stream, &mut buf) {
+                    Ok(0) => {}
+                    Ok(count) => {
+                        idle = false;
+                        let text = String::from_utf8_lossy(&buf[..count]);
+                        match prompt.watch(stream, &text, output) {
+                            Some(Prompted::Asked) => return Ok(Awaited::Prompted),
+                            Some(Prompted::Started) => return Ok(Awaited::NotAsked),
+                            None => {}
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => {
+                        return Err(TransportError::ExecutionFailed(format!(
+                            "failed waiting for the password prompt: {}",
+                            e
+                        )))
+                    }
+                }
+            }
+            if idle && channel.eof() {
+                prompt.finish(output);
+                return Ok(Awaited::NotAsked);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                prompt.finish(output);
+                return Ok(Awaited::TimedOut);
+            }
+            if idle {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    /**
+     * Run the command, giving up on it if the deadline passes
+     */
+    fn run(
+        &self,
+        command: &str,
+        escalation: &Escalation,
+        deadline: Option<Instant>,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Option<i32>, TransportError> {
+        let mut prompt = escalation.password_prompt();
+        let opened = self.open_exec(command, escalation, prompt.as_mut(), deadline, output);
+        let mut channel = match opened? {
+            Some(channel) => channel,
+            None => return Ok(None),
+        };
+        // The become method's announcement that the command has started may
+        // still be to come
+        let streamed = self.stream_output(&mut channel, deadline, &mut |stream, text| match prompt
+            .as_mut()
+        {
+            Some(prompt) => {
+                prompt.watch(stream, text, output);
+            }
+            None => output(stream, text),
+        });
+        if let Some(prompt) = prompt.as_mut() {
+            prompt.finish(output);
+        }
+        if !streamed? {
+            Self::abandon(&mut channel);
+            return Ok(None);
+        }
+        Self::finish(&mut channel).map(Some)
+    }
+
+    /**
+     * Stop waiting on a command which ran out of time. Closing our end of the
+     * channel doesn't stop the command, that is up to the caller
+     */
+    fn abandon(channel: &mut Channel) {
+        if let Err(err) = channel.close() {
+            debug!("Failed to close the channel: {}", err);
+        }
+    }
+
+    /**
+     * Pass along what the command writes to stdout and stderr as it arrives,
+     * until both have been closed, returning false if the deadline passed
+     * first.
+     *
+     * The streams are read in turn without blocking, so that a command which
+     * fills up the window of one of them isn't left stalled while we wait on
+     * the other.
+     */
+    fn stream_output(
+        &self,
+        channel: &mut Channel,
+        deadline: Option<Instant>,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<bool, TransportError> {
+        let session = self.session()?;
+        session.set_blocking(false);
+        let streamed = Self::pump(channel, deadline, output);
+        session.set_blocking(true);
+        streamed
+    }
+
+    fn pump<C: ExecChannel>(
+        channel: &mut C,
+        deadline: Option<Instant>,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<bool, TransportError> {
+        let (mut out, mut err) = (Decoder::default(), Decoder::default());
+        let mut buf = [0; 4096];
+        let mut finished = true;
+        loop {
+            let mut idle = true;
+            for stream in [Stream::Stdout, Stream::Stderr] {
+                let decoder = match stream {
+                    Stream::Stdout => &mut out,
+                    Stream::Stderr => &mut err,
+                };
+                match channel.read_stream(stream, &mut buf) {
+                    Ok(0) => {}
+                    Ok(count) => {
+                        idle = false;
+                        output(stream, &decoder.decode(&buf[..count]));
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => {
+                        return Err(TransportError::ExecutionFailed(format!(
+                            "failed to read the output: {}",
+                            e
+                        )))
+                    }
+                }
+            }
+            // Whatever was sent before the end of the output has been read
+            // by now
+            if idle && channel.eof() {
+                break;
+            }
+            // A command which never stops writing must still run out of time
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                finished = false;
+                break;
+            }
+            if idle {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+        output(Stream::Stdout, &out.finish());
+        output(Stream::Stderr, &err.finish());
+        Ok(finished)
+    }
+
+    /**
+     * Wait for the remote command to finish and return its exit status
+     */
+    fn finish(channel: &mut ssh2::Channel) -> Result<i32, TransportError> {
+        channel.wait_close().map_err(|e| {
+            TransportError::ExecutionFailed(format!("failed to close the channel: {}", e))
+        })?;
+        channel.exit_status().map_err(|e| {
+            TransportError::ExecutionFailed(format!("failed to get the exit status: {}", e))
+        })
+    }
+}
+
+/**
+ * How waiting on the become method's password prompt ended
+ */
+#[derive(Debug, PartialEq)]
+enum Awaited {
+    Prompted,
+    /// The command was let run, or the output ended, without the password
+    /// being asked for
+    NotAsked,
+    TimedOut,
+}
+
+/**
+ * The reading end of a channel which a command was executed on, which the
+ * tests can stand in for without a session
+ */
+trait ExecChannel {
+    fn read_stream(&mut self, stream: Stream, buf: &mut [u8]) -> std::io::Result<usize>;
+    fn eof(&self) -> bool;
+}
+
+impl ExecChannel for Channel {
+    fn read_stream(&mut self, stream: Stream, buf: &mut [u8]) -> std::io::Result<usize> {
+        match stream {
+            Stream::Stdout => self.read(buf),
+            Stream::Stderr => self.stderr().read(buf),
+        }
+    }
+
+    fn eof(&self) -> bool {
+        Channel::eof(self)
+    }
+}
+
+impl Transport for Ssh {
+    fn connect(&mut self, target: &Target) -> Result<(), TransportError> {
+        if self.session.is_some() {
+            return Ok(());
+        }
+        let endpoint = Endpoint::from_target(target)?;
+        let (session, jumps) = endpoint.open()?;
+        endpoint.verify(&session)?;
+        endpoint.authenticate(&session)?;
+        // Commands can take as long as they need, there are task timeouts for
+        // those which shouldn't
+        session.set_timeout(0);
+        session.set_keepalive(true, KEEPALIVE_INTERVAL);
+
+        self.session = Some(session);
+        self.jumps = jumps;
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        debug!("Disconnecting");
+        self.sftp = None;
+        self.sftp_unavailable = false;
+        // There doesn't seem to be any cleaner way to close other than
+        //.just dropping the session
+        if let Some(session) = self.session.take() {
+            if let Err(err) = session.disconnect(None, "Zappidy doo-da", None) {
+                debug!("Failed to cleanly disconnect: {}", err);
+            }
+        }
+        // The tunnels through the jump hosts shut down on their own once the
+        // session above has been dropped
+        self.jumps.clear();
+    }
+
+    /**
+     * libssh2 only sends a keepalive once the session has been idle for
+     * longer than the interval, so a dropped connection may not be noticed
+     * until it is next used
+     */
+    fn keepalive(&mut self) -> Result<(), TransportError> {
+        self.session()?
+            .keepalive_send()
+            .map(|_| ())
+            .map_err(|e| TransportError::ConnectionFailed(format!("the session was lost: {}", e)))
+    }
+
+    fn exec(
+        &mut self,
+        command: &str,
+        escalation: &Escalation,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<i32, TransportError> {
+        self.run(command, escalation, None, output)
+            .map(|status| status.unwrap_or(-1))
+    }
+
+    fn exec_until(
+        &mut self,
+        command: &str,
+        escalation: &Escalation,
+        deadline: Instant,
+        output: &mut dyn FnMut(Stream, &str),
+    ) -> Result<Option<i32>, TransportError> {
+        self.run(command, escalation, Some(deadline), output)
+    }
+
+    fn put(&mut self, remote_path: &Path, bytes: &[u8], mode: i32) -> Result<(), TransportError> {
+        let sftp = match self.sftp()? {
+            Some(sftp) => sftp,
+            None => return self.scp_put(remote_path, bytes, mode),
+        };
+        let transfer_failed =
+            |e| TransportError::TransferFailed(format!("{}: {}", remote_path.display(), e));
+
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        let mut remote_file = sftp
+            .open_mode(remote_path, flags, mode, OpenType::File)
+            .map_err(|e| transfer_failed(e.to_string()))?;
+        remote_file
+            .write_all(bytes)
+            .map_err(|e| transfer_failed(e.to_string()))?;
+        drop(remote_file);
+        // The mode is only used when the file is created, and then the umask
+        // has its say too
+        self.chmod(remote_path, mode)
+    }
+
+    fn get(&mut self, remote_path: &Path) -> Result<Vec<u8>, TransportError> {
+        let sftp = match self.sftp()? {
+            Some(sftp) => sftp,
+            None => return self.scp_get(remote_path),
+        };
+        let transfer_failed =
+            |e: String| TransportError::TransferFailed(format!("{}: {}", remote_path.display(), e));
+
+        let mut remote_file = sftp
+            .open(remote_path)
+            .map_err(|e| transfer_failed(e.to_string()))?;
+        let mut bytes = vec![];
+        remote_file
+            .read_to_end(&mut bytes)
+            .map_err(|e| transfer_failed(e.to_string()))?;
+        Ok(bytes)
+    }
+
+    fn stat(&mut self, path: &Path) -> Result<Option<FileStat>, TransportError> {
+        let sftp = match self.sftp()? {
+            Some(sftp) => sftp,
+            None => return self.shell_stat(path),
+        };
+        match sftp.stat(path) {
+            Ok(stat) => Ok(Some(FileStat {
+                size: stat.size.unwrap_or_default(),
+                mode: (stat.perm.unwrap_or_default() & 0o7777) as i32,
+                is_dir: stat.is_dir(),
+            })),
+            Err(err) if is_missing(&err) => {
+                debug!("The file ({}) does not exist", path.display());
+                Ok(None)
+            }
+            Err(err) => Err(TransportError::GeneralError(format!(
+                "failed to check {}: {}",
+                path.display(),
+                err
+            ))),
+        }
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<(), TransportError> {
+        let sftp = match self.sftp()? {
+            Some(sftp) => sftp,
+            None => {
+                let command = format!("rm -f {}", shell_quote(&path.to_string_lossy()));
+                return transport::run_ok(self, &command);
+            }
+        };
+        match sftp.unlink(path) {
+            Err(err) if !is_missing(&err) => Err(TransportError::GeneralError(format!(
+                "failed to remove {}: {}",
+                path.display(),
+                err
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn mkdir(&mut self, path: &Path, mode: i32) -> Result<(), TransportError> {
+        let sftp = match self.sftp()? {
+            Some(sftp) => sftp,
+            None => {
+                let command = format!(
+                    "mkdir -m {:o} {}",
+                    mode,
+                    shell_quote(&path.to_string_lossy())
+                );
+                return transport::run_ok(self, &command);
+            }
+        };
+        sftp.mkdir(path, mode).map_err(|e| {
+            TransportError::GeneralError(format!("failed to create {}: {}", path.display(), e))
+        })?;
+        // Like with put, the umask may have taken some of the mode away
+        self.chmod(path, mode)
+    }
+
+    fn chmod(&mut self, path: &Path, mode: i32) -> Result<(), TransportError> {
+        let stat = ssh2::FileStat {
+            perm: Some(mode as u32),
+            ..empty_stat()
+        };
+        self.setstat(path, stat, &format!("chmod {:o}", mode))
+    }
+
+    /**
+     * SFTP only knows about numeric ids, so owners given by name are changed
+     * with the shell
+     */
+    fn chown(&mut self, path: &Path, owner: &str) -> Result<(), TransportError> {
+        let ids = owner
+            .split_once(':')
+            .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)));
+        match ids {
+            Some((uid, gid)) => {
+                let stat = ssh2::FileStat {
+                    uid: Some(uid),
+                    gid: Some(gid),
+                    ..empty_stat()
+                };
+                self.setstat(path, stat, &format!("chown {}", shell_quote(owner)))
+            }
+            None => {
+                let command = format!(
+                    "chown {} {}",
+                    shell_quote(owner),
+                    shell_quote(&path.to_string_lossy())
+                );
+                transport::run_ok(self, &command)
+            }
+        }
+    }
+}
+
+fn empty_stat() -> ssh2::FileStat {
+    ssh2::FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: None,
+        atime: None,
+        mtime: None,
+    }
+}
+
+/**
+ * Whether the SFTP error means that the path doesn't exist
+ */
+fn is_missing(err: &ssh2::Error) -> bool {
+    matches!(
+        err.code(),
+        ErrorCode::SFTP(SFTP_NO_SUCH_FILE) | ErrorCode::SFTP(SFTP_NO_SUCH_PATH)
+    )
+}
+
+/**
+ * Parse the size, mode and type of a path out of its `ls -ldn` listing, e.g.
+ *
+ *  drwxr-x---  2 0 0 4096 Jan  1 00:00 /tmp/zap.XXXXXX
+ */
+fn parse_listing(listing: &str) -> Option<FileStat> {
+    let fields: Vec<&str> = listing.split_whitespace().collect();
+    let permissions = fields.first()?.as_bytes();
+    let size = fields.get(4)?.parse().ok()?;
+    if permissions.len() < 10 {
+        return None;
+    }
+
+    let mut mode = 0;
+    for (index, bit) in permissions[1..10].iter().enumerate() {
+        let shift = 8 - index;
+        match bit {
+            b'-' => {}
+            // setuid, setgid and sticky without execute
+            b'S' | b'T' => mode |= special_bit(index),
+            b's' | b't' => mode |= special_bit(index) | 1 << shift,
+            _ => mode |= 1 << shift,
+        }
+    }
+    Some(FileStat {
+        size,
+        mode,
+        is_dir: permissions[0] == b'd',
+    })
+}
+
+/// The setuid, setgid or sticky bit which shares a place in `ls` with execute
+fn special_bit(index: usize) -> i32 {
+    match index {
+        2 => 0o4000,
+        5 => 0o2000,
+        _ => 0o1000,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::escalation::BecomeMethod;
+    use crate::inventory::JumpHost;
+
+    #[test]
+    fn exec_without_connecting() {
+        let mut ssh = Ssh::default();
+        match ssh.exec("true", &Escalation::default(), &mut |_, _| {}) {
+            Err(TransportError::NotConnected) => {}
+            other => panic!("Expected NotConnected, got {:?}", other),
+        }
+    }
+
+    /**
+     * Stands in for the channel of a command, which writes the chunks of
+     * output in turn and then finishes, or else never does
+     */
+    struct Scripted {
+        output: Vec<(Stream, &'static str)>,
+        /// Write the same output over and over again
+        repeats: bool,
+        finishes: bool,
+    }
+
+    impl ExecChannel for Scripted {
+        fn read_stream(&mut self, stream: Stream, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.output.first() {
+                Some((next, text)) if *next == stream => {
+                    let text = *text;
+                    buf[..text.len()].copy_from_slice(text.as_bytes());
+                    if !self.repeats {
+                        self.output.remove(0);
+                    }
+                    Ok(text.len())
+                }
+                _ => Err(ErrorKind::WouldBlock.into()),
+            }
+        }
+
+        fn eof(&self) -> bool {
+            self.finishes && self.output.is_empty()
+        }
+    }
+
+    #[test]
+    fn pump_until_deadline_while_writing() {
+        let mut channel = Scripted {
+            output: vec![(Stream::Stdout, "y\n")],
+            repeats: true,
+            finishes: false,
+        };
+        let started = Instant::now();
+        let mut lines = 0;
+        let finished = Ssh::pump(
+            &mut channel,
+            Some(started + Duration::from_millis(100)),
+            &mut |_, text| lines += text.matches('\n').count(),
+        )
+        .expect("Failed to pump");
+        assert!(!finished);
+        assert!(lines > 0);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    fn escalation(method: BecomeMethod) -> Escalation {
+        Escalation {
+            enabled: Some(true),
+            method: Some(method),
+            user: None,
+            password: Some("hunter2".into()),
+        }
+    }
+
+    fn await_prompt(
+        channel: &mut Scripted,
+        method: BecomeMethod,
+        deadline: Option<Instant>,
+    ) -> (Awaited, String) {
+        let mut prompt = escalation(method).password_prompt().unwrap();
+        let mut seen = String::new();
+        let awaited = Ssh::await_prompt(channel, &mut prompt, deadline, &mut |_, text| {
+            seen.push_str(text)
+        })
+        .expect("Failed to wait");
+        (awaited, seen)
+    }
+
+    #[test]
+    fn await_tty_prompt() {
+        let mut channel = Scripted {
+            output: vec![
+                (Stream::Stdout, "Last login\r\nPass"),
+                (Stream::Stdout, "word: "),
+            ],
+            repeats: false,
+            finishes: false,
+        };
+        let awaited = await_prompt(&mut channel, BecomeMethod::Su, None);
+        assert_eq!(awaited, (Awaited::Prompted, "Last login\r\n".into()));
+
+        let mut channel = Scripted {
+            output: vec![(Stream::Stdout, "done\r\n")],
+            repeats: false,
+            finishes: true,
+        };
+        let awaited = await_prompt(&mut channel, BecomeMethod::Doas, None);
+        assert_eq!(awaited, (Awaited::NotAsked, "done\r\n".into()));
+    }
+
+    #[test]
+    fn await_sudo_prompt() {
+        let mut channel = Scripted {
+            output: vec![(Stream::Stderr, "[zap] sudo password:")],
+            repeats: false,
+            finishes: false,
+        };
+        let awaited = await_prompt(&mut channel, BecomeMethod::Sudo, None);
+        assert_eq!(awaited, (Awaited::Prompted, String::new()));
+
+        // sudo didn't need the password, so the command must not be sent it
+        let mut channel = Scripted {
+            output: vec![(Stream::Stderr, "zap-escalated\nzap-pid 4242\n")],
+            repeats: false,
+            finishes: false,
+        };
+        let awaited = await_prompt(&mut channel, BecomeMethod::Sudo, None);
+        assert_eq!(awaited, (Awaited::NotAsked, "zap-pid 4242\n".into()));
+    }
+
+    #[test]
+    fn await_prompt_until_deadline() {
+        let mut channel = Scripted {
+            output: vec![(Stream::Stdout, "zap-pid 4242\r\n")],
+            repeats: false,
+            finishes: false,
+        };
+        let started = Instant::now();
+        let awaited = await_prompt(
+            &mut channel,
+            BecomeMethod::Doas,
+            Some(started + Duration::from_millis(100)),
+        );
+        assert_eq!(awaited, (Awaited::TimedOut, "zap-pid 4242\r\n".into()));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn parse_ls_listings() {
+        assert_eq!(
+            parse_listing("drwx--x--x  2 1001 1001 4096 Jan  1 00:00 /tmp/zap.abc123\n"),
+            Some(FileStat {
+                size: 4096,
+                mode: 0o711,
+                is_dir: true,
+            })
+        );
+        assert_eq!(
+            parse_listing("-rwsr-x--T  1 0 0 27 Jan  1 00:00 ._zap_command"),
+            Some(FileStat {
+                size: 27,
+                mode: 0o5750,
+                is_dir: false,
+            })
+        );
+        assert_eq!(parse_listing("ls: cannot access"), None);
+    }
+
+    #[test]
+    fn resolve_endpoint_precedence() {
+        let client: ClientConfig = r#"
+Host prod-db-1
+    HostName 10.0.0.5
+    User dba
+    Port 2222
+    IdentityFile /keys/db
+"#
+        .parse()
+        .unwrap();
+        let uri = TargetUri {
+            scheme: "ssh".into(),
+            user: None,
+            host: "prod-db-1".into(),
+            port: None,
+        };
+
+        let endpoint = Endpoint::resolve(&uri, None, &client).expect("Failed to resolve");
+        assert_eq!(endpoint.host, "10.0.0.5");
+        assert_eq!(endpoint.user, "dba");
+        assert_eq!(endpoint.port, 2222);
+        assert_eq!(endpoint.identity_files, vec![PathBuf::from("/keys/db")]);
+
+        let sshconfig = SshConfig {
+            user: Some("root".into()),
+            port: Some(22),
+            ..Default::default()
+        };
+        let endpoint =
+            Endpoint::resolve(&uri, Some(&sshconfig), &client).expect("Failed to resolve");
+        assert_eq!(endpoint.user, "root");
+        assert_eq!(endpoint.port, 22);
+
+        let uri = TargetUri {
+            user: Some("deploy".into()),
+            port: Some(2200),
+            ..uri
+        };
+        let endpoint =
+            Endpoint::resolve(&uri, Some(&sshconfig), &client).expect("Failed to resolve");
+        assert_eq!(endpoint.user, "deploy");
+        assert_eq!(endpoint.port, 2200);
+    }
+
+    #[test]
+    fn resolve_endpoint_jumps() {
+        let client: ClientConfig = r#"
+Host prod-*
+    ProxyJump bastion
+    User deploy
+
+Host bastion
+    HostName 203.0.113.10
+    User jumper
+"#
+        .parse()
+        .unwrap();
+        let uri: TargetUri = "prod-web-1".parse().unwrap();
+
+        let endpoint = Endpoint::resolve(&uri, None, &client).expect("Failed to resolve");
+        assert_eq!(endpoint.jumps.len(), 1);
+        assert_eq!(endpoint.jumps[0].host, "203.0.113.10");
+        assert_eq!(endpoint.jumps[0].user, "jumper");
+
+        let sshconfig = SshConfig {
+            proxy_jump: Some(ProxyJump::Hops(vec![
+                JumpHost {
+                    uri: "bastion".into(),
+                    password: None,
+                    privatekey_path: Some("/keys/bastion".into()),
+                },
+                JumpHost {
+                    uri: "root@inner:2222".into(),
+                    password: Some("hunter2".into()),
+                    privatekey_path: None,
+                },
+            ])),
+            host_key_policy: Some(HostKeyPolicy::AcceptNew),
+            connect_timeout: Some(5),
+            ..Default::default()
+        };
+        let endpoint =
+            Endpoint::resolve(&uri, Some(&sshconfig), &client).expect("Failed to resolve");
+        assert_eq!(endpoint.connect_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(endpoint.jumps.len(), 2);
+        assert_eq!(
+            endpoint.jumps[0].privatekey_path,
+            Some(PathBuf::from("/keys/bastion"))
+        );
+        assert_eq!(endpoint.jumps[1].user, "root");
+        assert_eq!(endpoint.jumps[1].port, 2222);
+        assert_eq!(endpoint.jumps[1].password, Some("hunter2".into()));
+        assert_eq!(endpoint.jumps[1].host_key_policy, HostKeyPolicy::AcceptNew);
+        assert_eq!(endpoint.jumps[1].connect_timeout, endpoint.connect_timeout);
+
+        let sshconfig = SshConfig {
+            proxy_jump: Some(ProxyJump::Chain("none".into())),
+            ..Default::default()
+        };
+        let endpoint =
+            Endpoint::resolve(&uri, Some(&sshconfig), &client).expect("Failed to resolve");
+        assert!(endpoint.jumps.is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+use log::*;
+use ssh2::{Channel, Session};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/**
+ * How long the pump sleeps when neither side of the tunnel had anything to
+ * move, so an idle tunnel doesn't spin a core
+ */
+const IDLE_WAIT: Duration = Duration::from_millis(2);
+
+/**
+ * Open a `direct-tcpip` channel from the jump session to the given host and
+ * return a local socket which is connected through it.
+ *
+ * libssh2 can only run a session over a real socket, so the channel is
+ * bridged to one end of a loopback socket pair by a background thread and the
+ * other end is handed back to be used for the next session's handshake. The
+ * thread exits once either end of the tunnel is closed.
+ *
+ * Opening the channel is subject to the jump session's timeout, if it has one,
+ * after which the timeout is cleared and the session is switched into
+ * non-blocking mode. It must not be used for anything else afterwards.
+ */
+pub fn open(jump: &Session, host: &str, port: u16) -> Result<TcpStream, String> {
+    let channel = jump
+        .channel_direct_tcpip(host, port, None)
+        .map_err(|e| format!("failed to open a tunnel to {}:{}: {}", host, port, e))?;
+    // The session through the tunnel can take as long as it needs
+    jump.set_timeout(0);
+    let (local, remote) = socket_pair().map_err(|e| format!("failed to create a socket: {}", e))?;
+
+    let session = jump.clone();
+    let name = format!("{}:{}", host, port);
+    thread::Builder::new()
+        .name(format!("tunnel-{}", name))
+        .spawn(move || {
+            if let Err(err) = pump(session, channel, remote) {
+                debug!("The tunnel to {} closed: {}", name, err);
+            }
+        })
+        .map_err(|e| format!("failed to start the tunnel: {}", e))?;
+    Ok(local)
+}
+
+/**
+ * Create a pair of connected loopback sockets, making sure that the accepted
+ * connection really is our own and not some other local process racing us
+ */
+fn socket_pair() -> std::io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let local = TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        let (remote, peer) = listener.accept()?;
+        if peer == local.local_addr()? {
+            return Ok((local, remote));
+        }
+        warn!(
+            "Rejecting unexpected connection to the tunnel from {}",
+            peer
+        );
+    }
+}
+
+/**
+ * Shuttle bytes between the channel and the socket until either side closes
+ */
+fn pump(session: Session, mut channel: Channel, mut socket: TcpStream) -> std::io::Result<()> {
+    session.set_blocking(false);
+    socket.set_nonblocking(true)?;
+
+    let mut buf = [0; 16384];
+    // Bytes read from one side which the other side hasn't accepted yet
+    let mut upstream: Vec<u8> = vec![];
+    let mut downstream: Vec<u8> = vec![];
+
+    loop {
+        let mut progressed = false;
+
+        if upstream.is_empty() {
+            match socket.read(&mut buf) {
+                Ok(0) => break,
+                Ok(count) => {
+                    upstream.extend_from_slice(&buf[..count]);
+                    progressed = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !upstream.is_empty() {
+            match channel.write(&upstream) {
+                Ok(count) => {
+                    upstream.drain(..count);
+                    progressed = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if downstream.is_empty() {
+            match channel.read(&mut buf) {
+                Ok(0) if channel.eof() => break,
+                Ok(0) => {}
+                Ok(count) => {
+                    downstream.extend_from_slice(&buf[..count]);
+                    progressed = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !downstream.is_empty() {
+            match socket.write(&downstream) {
+                Ok(count) => {
+                    downstream.drain(..count);
+                    progressed = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if !progressed {
+            thread::sleep(IDLE_WAIT);
+        }
+    }
+
+    let _ = channel.close();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_pair_is_connected() {
+        let (mut local, mut remote) = socket_pair().expect("Failed to create a socket pair");
+        local.write_all(b"zap").unwrap();
+        let mut buf = [0; 3];
+        remote.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"zap");
+    }
+}